@@ -0,0 +1,14 @@
+// Fades images in over their blurred placeholders as they finish loading. Images that have
+// already loaded by the time this runs are left alone, and without javascript the images just
+// appear over their placeholders as they load.
+(function () {
+  document.querySelectorAll("figure.placeholder img").forEach((img) => {
+    if (img.complete) {
+      return;
+    }
+    img.classList.add("loading");
+    const loaded = () => img.classList.remove("loading");
+    img.addEventListener("load", loaded, { once: true });
+    img.addEventListener("error", loaded, { once: true });
+  });
+})();
@@ -0,0 +1,74 @@
+// Turns the stacked panels of `:::tabs` groups into tabs. Picking a tab picks the tab with the
+// same label in every other group on the page (and is remembered for next time), so that readers
+// following along in one language only have to choose it once.
+(function () {
+  const STORAGE_KEY = "tabs-label";
+  const groups = [];
+
+  function select(label, store) {
+    for (const group of groups) {
+      if (!group.tabs.some((tab) => tab.dataset.label === label)) {
+        continue;
+      }
+      group.tabs.forEach((tab, i) => {
+        const selected = tab.dataset.label === label;
+        tab.setAttribute("aria-selected", selected ? "true" : "false");
+        tab.tabIndex = selected ? 0 : -1;
+        group.panels[i].hidden = !selected;
+      });
+    }
+    if (store) {
+      try {
+        localStorage.setItem(STORAGE_KEY, label);
+      } catch (e) {}
+    }
+  }
+
+  document.querySelectorAll(".tabs").forEach((element, g) => {
+    const panels = Array.from(element.children).filter((child) =>
+      child.classList.contains("tab-panel")
+    );
+    const list = document.createElement("div");
+    list.className = "tab-list";
+    list.setAttribute("role", "tablist");
+
+    const tabs = panels.map((panel, i) => {
+      const id = `tabs-${g}-${i}`;
+      const tab = document.createElement("button");
+      tab.type = "button";
+      tab.id = `${id}-tab`;
+      tab.textContent = panel.dataset.label;
+      tab.dataset.label = panel.dataset.label;
+      tab.setAttribute("role", "tab");
+      tab.setAttribute("aria-controls", id);
+      tab.addEventListener("click", () => select(tab.dataset.label, true));
+      tab.addEventListener("keydown", (event) => {
+        const step = { ArrowRight: 1, ArrowLeft: -1 }[event.key];
+        if (step) {
+          const next = tabs[(i + step + tabs.length) % tabs.length];
+          select(next.dataset.label, true);
+          next.focus();
+        }
+      });
+      list.appendChild(tab);
+
+      panel.id = id;
+      panel.setAttribute("role", "tabpanel");
+      panel.setAttribute("aria-labelledby", tab.id);
+      return tab;
+    });
+
+    element.insertBefore(list, element.firstChild);
+    element.classList.add("tabbed");
+    groups.push({ tabs, panels });
+    select(tabs[0].dataset.label, false);
+  });
+
+  let stored = null;
+  try {
+    stored = localStorage.getItem(STORAGE_KEY);
+  } catch (e) {}
+  if (stored) {
+    select(stored, false);
+  }
+})();
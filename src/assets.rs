@@ -0,0 +1,196 @@
+//! The static files under `assets/` that are copied into the site as they are, and checks that
+//! the site only refers to ones which exist (and which ones nothing refers to at all).
+use crate::check::{internal_link, percent_decode, unescape_html, Site};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+lazy_static::lazy_static! {
+    /// urls in html attributes, css `url()`s and web manifest `"src"`s
+    static ref REFERENCE_REGEX: regex::Regex = regex::Regex::new(r#"(?:\s(?:href|src|poster|data)\s*=\s*["']|url\(\s*["']?|"src"\s*:\s*")([^"'()\s<>]+)"#).expect("valid regex");
+}
+
+/// assets that browsers ask for without anything referring to them
+const IMPLICITLY_USED: &[&str] = &[
+    "/favicon.ico",
+    "/browserconfig.xml",
+    "/robots.txt",
+    "/CNAME",
+];
+
+/// a file under `assets/`
+pub struct Asset {
+    pub source: PathBuf,
+    /// where the asset is served from, i.e. `/fonts/some-font.woff2`
+    pub url: String,
+    pub size: u64,
+}
+
+/// every asset under `dir` (skipping any markdown notes)
+pub fn list<P: AsRef<Path>>(dir: P) -> Result<Vec<Asset>, Box<dyn std::error::Error>> {
+    let mut assets: Vec<Asset> = Vec::default();
+    for entry in ignore::Walk::new(dir.as_ref()) {
+        let entry = entry?;
+        if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+            continue;
+        }
+        if let Some("md") = entry
+            .path()
+            .extension()
+            .map(std::ffi::OsStr::to_str)
+            .flatten()
+        {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(dir.as_ref())?;
+        assets.push(Asset {
+            source: entry.path().to_owned(),
+            url: format!("/{}", relative.to_string_lossy().replace('\\', "/")),
+            size: entry.metadata()?.len(),
+        });
+    }
+    assets.sort_by(|a, b| a.url.cmp(&b.url));
+    Ok(assets)
+}
+
+/// assets that can refer to other assets (scripts only by name, see `validate`)
+fn is_text(asset: &Asset) -> bool {
+    matches!(
+        asset
+            .source
+            .extension()
+            .map(std::ffi::OsStr::to_str)
+            .flatten(),
+        Some("css") | Some("js") | Some("json") | Some("webmanifest") | Some("xml") | Some("svg")
+    )
+}
+
+/// the directory part of a url, including its trailing `/`
+fn url_dir(url: &str) -> &str {
+    &url[..url.rfind('/').map(|i| i + 1).unwrap_or_default()]
+}
+
+/// resolve a url relative to the url of the file it is in, giving a root-relative url
+fn resolve_url(base: &str, url: &str) -> String {
+    let joined = if url.starts_with('/') {
+        url.to_owned()
+    } else {
+        format!("{}{}", url_dir(base), url)
+    };
+    let mut segments: Vec<&str> = Vec::default();
+    for segment in joined.split('/').skip(1) {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// the root-relative urls of the files (rather than pages) that some text served from `base`
+/// refers to
+fn references(text: &str, base: &str) -> BTreeSet<String> {
+    REFERENCE_REGEX
+        .captures_iter(text)
+        .filter_map(|caps| {
+            // json escapes its slashes
+            let url = unescape_html(&caps[1]).replace("\\/", "/");
+            let url = internal_link(&url)?.split(&['?', '#'][..]).next()?;
+            let url = percent_decode(url);
+            if !url.rsplit('/').next()?.contains('.') {
+                return None;
+            }
+            Some(resolve_url(base, &url))
+        })
+        .collect()
+}
+
+/// Cross-reference the assets against what the site's pages (and the assets themselves) refer
+/// to, returning the references to files that don't exist and the assets that nothing refers to.
+/// Only the files in `generated` (the urls of what the build wrote) count as existing, so that
+/// anything left in `docs` by earlier builds can't hide a missing file.
+pub fn validate<'a>(
+    assets: &'a [Asset],
+    site: &Site,
+    generated: &BTreeSet<String>,
+) -> (Vec<String>, Vec<&'a Asset>) {
+    let urls: BTreeSet<&str> = assets.iter().map(|asset| asset.url.as_str()).collect();
+    let mut used: BTreeSet<String> = IMPLICITLY_USED.iter().map(|u| u.to_string()).collect();
+    // every page has the same styles inlined, so missing files are reported once with whatever
+    // refers to them
+    let mut missing: BTreeMap<String, Vec<String>> = BTreeMap::default();
+    let mut check = |name: &Path, url: &str, text: &str| {
+        for reference in references(text, url) {
+            if !urls.contains(reference.as_str()) && !generated.contains(&reference) {
+                missing
+                    .entry(reference.clone())
+                    .or_default()
+                    .push(name.display().to_string());
+            }
+            used.insert(reference);
+        }
+    };
+
+    for page in site.pages.iter() {
+        check(&page.path, &page.url, &page.html);
+    }
+    let texts: Vec<(&Asset, String)> = assets
+        .iter()
+        .filter(|asset| is_text(asset))
+        .filter_map(|asset| Some((asset, std::fs::read_to_string(&asset.source).ok()?)))
+        .collect();
+    for (asset, text) in texts.iter() {
+        // scripts build their urls in code rather than writing them out
+        if !asset.url.ends_with(".js") {
+            check(&asset.source, &asset.url, text);
+        }
+    }
+    // scripts load their chunks by name, and source maps are named in a comment
+    for (text_asset, text) in texts.iter() {
+        let dir = url_dir(&text_asset.url);
+        for asset in assets.iter().filter(|a| url_dir(&a.url) == dir) {
+            if asset.url != text_asset.url && text.contains(&asset.url[dir.len()..]) {
+                used.insert(asset.url.clone());
+            }
+        }
+    }
+
+    let missing = missing
+        .into_iter()
+        .map(|(reference, names)| match names.len() {
+            1 => format!(
+                "`{}` doesn't exist, but `{}` refers to it",
+                reference, names[0]
+            ),
+            n => format!(
+                "`{}` doesn't exist, but `{}` and {} other files refer to it",
+                reference,
+                names[0],
+                n - 1
+            ),
+        })
+        .collect();
+    let unused = assets
+        .iter()
+        .filter(|asset| !used.contains(&asset.url))
+        .collect();
+    (missing, unused)
+}
+
+/// `1.2 MiB` and so on
+pub fn human_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB"].iter() {
+        if size < 1024.0 {
+            return if *unit == "B" {
+                format!("{} {}", bytes, unit)
+            } else {
+                format!("{:.1} {}", size, unit)
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} GiB", size)
+}
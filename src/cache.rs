@@ -0,0 +1,85 @@
+//! A simple on-disk cache for expensive renders (diagrams, etc), keyed on a hash of their input
+//! so that changing the input automatically misses the cache.
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const CACHE_DIR: &str = ".cache";
+
+/// Hash some input into a cache key. This is FNV-1a rather than std's hasher, which is free to
+/// change between Rust releases and would throw the whole cache away when it did.
+pub fn hash_key<K: AsRef<[u8]> + ?Sized>(key: &K) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.as_ref() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+pub fn cache_path(kind: &str, key: &str) -> PathBuf {
+    PathBuf::from(CACHE_DIR).join(kind).join(hash_key(key))
+}
+
+/// Write a file by writing a temporary file next to it and renaming that into place, so that
+/// anything reading it at the same time (renders run in parallel) never sees half of it
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &[u8]) -> std::io::Result<()> {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+    let path = path.as_ref();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, path).map_err(|e| {
+        let _ = std::fs::remove_file(&temp);
+        e
+    })
+}
+
+/// load the rendered output for `key` from the cache, rendering & storing it with `render` if
+/// it isn't there yet
+pub fn cached<F>(kind: &str, key: &str, render: F) -> Result<String, Box<dyn std::error::Error>>
+where
+    F: FnOnce() -> Result<String, Box<dyn std::error::Error>>,
+{
+    let rendered = cached_bytes(kind, key, || render().map(String::into_bytes))?;
+    Ok(String::from_utf8(rendered)?)
+}
+
+/// like `cached`, for binary outputs such as images
+pub fn cached_bytes<F>(
+    kind: &str,
+    key: &str,
+    render: F,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+where
+    F: FnOnce() -> Result<Vec<u8>, Box<dyn std::error::Error>>,
+{
+    let path = cache_path(kind, key);
+    if let Ok(contents) = std::fs::read(&path) {
+        return Ok(contents);
+    }
+
+    let rendered = render()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    write_atomic(&path, &rendered)?;
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_keys_are_stable() {
+        assert_eq!(hash_key(""), "cbf29ce484222325");
+        assert_eq!(hash_key("a"), "af63dc4c8601ec8c");
+        assert_eq!(hash_key("foobar"), "85944171f73967e8");
+        assert_eq!(hash_key(&b"foobar".to_vec()), hash_key("foobar"));
+    }
+}
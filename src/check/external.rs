@@ -0,0 +1,283 @@
+use super::{internal_link, links, locate, Site};
+use crate::config::ExternalLinks;
+use crate::post::Post;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// what happened when a link was last checked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum LinkStatus {
+    Alive,
+    Redirected(String),
+    Dead(String),
+    /// the server couldn't be reached at all (a timeout, dns failure, etc) which may well pass,
+    /// so unlike a definite answer from the server this is checked again on every run
+    Unreachable(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CheckedLink {
+    checked: DateTime<Utc>,
+    status: LinkStatus,
+}
+
+fn cache_file() -> PathBuf {
+    PathBuf::from(crate::cache::CACHE_DIR).join("external-links.yml")
+}
+
+/// `https://example.com` from `https://example.com/some/page`
+fn origin(url: &str) -> &str {
+    let start = url.find("://").map(|i| i + 3).unwrap_or_default();
+    match url[start..].find('/') {
+        Some(i) => &url[..start + i],
+        None => url,
+    }
+}
+
+fn check_link(agent: &ureq::Agent, url: &str) -> LinkStatus {
+    let response = match agent.head(url).call() {
+        // plenty of servers don't answer HEAD requests properly, so ask again with a GET before
+        // calling the link dead
+        Err(ureq::Error::Status(_, _)) => agent.get(url).call(),
+        response => response,
+    };
+    match response {
+        Ok(response) if (300..400).contains(&response.status()) => {
+            match response.header("location") {
+                Some(location) if location.starts_with('/') => {
+                    LinkStatus::Redirected(format!("{}{}", origin(url), location))
+                }
+                Some(location) => LinkStatus::Redirected(location.to_owned()),
+                None => LinkStatus::Dead(format!(
+                    "{} {} without a location",
+                    response.status(),
+                    response.status_text()
+                )),
+            }
+        }
+        Ok(_) => LinkStatus::Alive,
+        Err(ureq::Error::Status(code, response)) => {
+            LinkStatus::Dead(format!("{} {}", code, response.status_text()))
+        }
+        Err(ureq::Error::Transport(e)) => LinkStatus::Unreachable(e.to_string()),
+    }
+}
+
+/// whether a link needs checking (again) rather than using what the cache says about it
+fn is_stale(cached: Option<&CheckedLink>, now: DateTime<Utc>, cache_days: i64) -> bool {
+    match cached {
+        Some(CheckedLink {
+            status: LinkStatus::Unreachable(_),
+            ..
+        }) => true,
+        Some(cached) => now - cached.checked > chrono::Duration::days(cache_days),
+        None => true,
+    }
+}
+
+/// Check that the links to other sites still lead somewhere, returning a message for each one
+/// that is dead, redirected or unreachable, in order of the pages they are on. Answers from the
+/// servers are cached in `.cache/` for `cache_days` so that re-running the check only checks new
+/// or stale links, or ones that couldn't be reached last time.
+pub fn check_external_links(
+    site: &Site,
+    posts: &[Post],
+    config: &ExternalLinks,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    use rayon::prelude::*;
+
+    let is_external = |link: &str| {
+        internal_link(link).is_none()
+            && (link.starts_with("http://") || link.starts_with("https://"))
+            && !config.ignore.iter().any(|prefix| link.starts_with(prefix))
+    };
+    let urls: BTreeSet<String> = site
+        .pages
+        .iter()
+        .flat_map(|page| links(&page.html))
+        .filter(|link| is_external(link))
+        .collect();
+
+    let mut cache: BTreeMap<String, CheckedLink> = match std::fs::read_to_string(cache_file()) {
+        Ok(contents) => serde_yaml::from_str(&contents)?,
+        Err(_) => BTreeMap::default(),
+    };
+    let now = Utc::now();
+    let stale: Vec<&String> = urls
+        .iter()
+        .filter(|url| is_stale(cache.get(*url), now, config.cache_days))
+        .collect();
+    println!(
+        "Checking {} external links ({} cached)...",
+        stale.len(),
+        urls.len() - stale.len()
+    );
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(config.timeout))
+        .redirects(0)
+        .build();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.concurrency.max(1))
+        .build()?;
+    let checked: Vec<(String, CheckedLink)> = pool.install(|| {
+        stale
+            .par_iter()
+            .map(|url| {
+                let status = check_link(&agent, url);
+                (
+                    url.to_string(),
+                    CheckedLink {
+                        checked: Utc::now(),
+                        status,
+                    },
+                )
+            })
+            .collect()
+    });
+    cache.extend(checked);
+    // forget links that nothing links to any more
+    cache.retain(|url, _| urls.contains(url));
+    std::fs::create_dir_all(crate::cache::CACHE_DIR)?;
+    std::fs::write(cache_file(), serde_yaml::to_string(&cache)?)?;
+
+    let mut problems: Vec<String> = Vec::default();
+    for page in site.pages.iter() {
+        let mut seen: BTreeSet<String> = BTreeSet::default();
+        for link in links(&page.html) {
+            if !is_external(&link) || !seen.insert(link.clone()) {
+                continue;
+            }
+            let problem = match cache.get(&link).map(|c| &c.status) {
+                Some(LinkStatus::Redirected(location)) => {
+                    format!("redirected link `{}` now goes to `{}`", link, location)
+                }
+                Some(LinkStatus::Dead(reason)) => format!("dead link `{}`: {}", link, reason),
+                Some(LinkStatus::Unreachable(reason)) => {
+                    format!("couldn't reach `{}`: {}", link, reason)
+                }
+                _ => continue,
+            };
+            problems.push(format!("{}: {}", locate(page, &link, posts), problem));
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+
+    /// answer each connection with the next of `responses`, returning the server's address
+    fn serve(responses: Vec<String>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                // read the request up to the blank line after its headers before answering
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::default();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    fn response(status: &str, headers: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            status, headers
+        )
+    }
+
+    fn agent() -> ureq::Agent {
+        ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(5))
+            .redirects(0)
+            .build()
+    }
+
+    #[test]
+    fn live_links_are_alive() {
+        let url = serve(vec![response("200 OK", "")]);
+        assert!(matches!(check_link(&agent(), &url), LinkStatus::Alive));
+    }
+
+    #[test]
+    fn links_that_dont_answer_head_requests_are_asked_again() {
+        let url = serve(vec![
+            response("405 Method Not Allowed", ""),
+            response("200 OK", ""),
+        ]);
+        assert!(matches!(check_link(&agent(), &url), LinkStatus::Alive));
+    }
+
+    #[test]
+    fn missing_pages_are_dead() {
+        let url = serve(vec![
+            response("404 Not Found", ""),
+            response("404 Not Found", ""),
+        ]);
+        match check_link(&agent(), &url) {
+            LinkStatus::Dead(reason) => assert_eq!(reason, "404 Not Found"),
+            status => panic!("expected a dead link, got {:?}", status),
+        }
+    }
+
+    #[test]
+    fn redirects_are_resolved_against_the_origin() {
+        let url = serve(vec![response(
+            "301 Moved Permanently",
+            "Location: /new/page\r\n",
+        )]);
+        match check_link(&agent(), &format!("{}/old/page", url)) {
+            LinkStatus::Redirected(location) => {
+                assert_eq!(location, format!("{}/new/page", url))
+            }
+            status => panic!("expected a redirect, got {:?}", status),
+        }
+    }
+
+    #[test]
+    fn servers_that_cant_be_reached_are_unreachable() {
+        // nothing is listening once the listener is dropped
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(matches!(
+            check_link(&agent(), &url),
+            LinkStatus::Unreachable(_)
+        ));
+    }
+
+    #[test]
+    fn only_answers_from_servers_are_cached() {
+        let now = Utc::now();
+        let checked = |days: i64, status: LinkStatus| CheckedLink {
+            checked: now - chrono::Duration::days(days),
+            status,
+        };
+        assert!(is_stale(None, now, 7));
+        assert!(!is_stale(Some(&checked(1, LinkStatus::Alive)), now, 7));
+        assert!(!is_stale(
+            Some(&checked(1, LinkStatus::Dead("404 Not Found".to_owned()))),
+            now,
+            7
+        ));
+        assert!(is_stale(Some(&checked(8, LinkStatus::Alive)), now, 7));
+        assert!(is_stale(
+            Some(&checked(0, LinkStatus::Unreachable("timed out".to_owned()))),
+            now,
+            7
+        ));
+    }
+}
@@ -0,0 +1,134 @@
+use super::{internal_link, links, locate, percent_decode, Page, Site};
+use crate::post::Post;
+use std::path::{Path, PathBuf};
+
+/// the file a link within the site is served from, along with the `#fragment` it points at
+fn resolve(site: &Site, page: &Page, link: &str) -> (PathBuf, String) {
+    let (path, fragment) = match link.find('#') {
+        Some(i) => (&link[..i], percent_decode(&link[i + 1..])),
+        None => (link, String::default()),
+    };
+    // the query comes before the fragment
+    let path = percent_decode(path.split('?').next().unwrap_or_default());
+
+    let mut file = if path.is_empty() {
+        page.path.clone()
+    } else if let Some(absolute) = path.strip_prefix('/') {
+        site.root.join(absolute)
+    } else {
+        page.path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(&path)
+    };
+    if path.ends_with('/') || file.is_dir() {
+        file = file.join("index.html");
+    }
+    (file, fragment)
+}
+
+/// Check that every `href` and `src` within the site leads to a generated file, and that any
+/// `#fragment` on it names an element in that file, returning a message for each one that
+/// doesn't
+pub fn check_internal_links(site: &Site, posts: &[Post]) -> Vec<String> {
+    use rayon::prelude::*;
+
+    site.pages
+        .par_iter()
+        .flat_map_iter(|page| {
+            links(&page.html)
+                .into_iter()
+                .filter_map(|link| {
+                    let internal = internal_link(&link)?;
+                    let (file, fragment) = resolve(site, page, internal);
+                    let problem = if !file.is_file() {
+                        format!("`{}` doesn't exist", file.display())
+                    } else if fragment.is_empty() {
+                        return None;
+                    } else {
+                        match site.ids(&file) {
+                            Some(ids) if !ids.contains(&fragment) => {
+                                format!(
+                                    "`{}` has no element with the id `{}`",
+                                    file.display(),
+                                    fragment
+                                )
+                            }
+                            _ => return None,
+                        }
+                    };
+                    Some(format!(
+                        "{}: broken link `{}`: {}",
+                        locate(page, &link, posts),
+                        link,
+                        problem
+                    ))
+                })
+                .collect::<Vec<String>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn resolve_from_post(link: &str) -> (PathBuf, String) {
+        let site = Site {
+            root: PathBuf::from("/site"),
+            pages: Vec::default(),
+            ids: HashMap::default(),
+        };
+        let page = Page {
+            path: PathBuf::from("/site/posts/a/index.html"),
+            url: "/posts/a/".to_owned(),
+            html: String::default(),
+        };
+        resolve(&site, &page, link)
+    }
+
+    #[test]
+    fn resolves_absolute_and_relative_links() {
+        assert_eq!(
+            resolve_from_post("/about/"),
+            (PathBuf::from("/site/about/index.html"), String::default())
+        );
+        assert_eq!(
+            resolve_from_post("diagram.svg"),
+            (
+                PathBuf::from("/site/posts/a/diagram.svg"),
+                String::default()
+            )
+        );
+        assert_eq!(
+            resolve_from_post("../b/"),
+            (
+                PathBuf::from("/site/posts/a/../b/index.html"),
+                String::default()
+            )
+        );
+    }
+
+    #[test]
+    fn splits_off_fragments_and_queries() {
+        assert_eq!(
+            resolve_from_post("#intro"),
+            (
+                PathBuf::from("/site/posts/a/index.html"),
+                "intro".to_owned()
+            )
+        );
+        assert_eq!(
+            resolve_from_post("/posts/b/?page=2#a%20b"),
+            (PathBuf::from("/site/posts/b/index.html"), "a b".to_owned())
+        );
+        assert_eq!(
+            resolve_from_post("my%20image.png?v=1"),
+            (
+                PathBuf::from("/site/posts/a/my image.png"),
+                String::default()
+            )
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! Checks over the site generated in `docs/`, so that links which don't lead anywhere are caught
+//! before they ship rather than after. Links to other sites are only checked when asked to, as
+//! that means going out to the network.
+mod external;
+mod internal;
+
+pub use external::check_external_links;
+pub use internal::check_internal_links;
+
+use crate::post::Post;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+lazy_static::lazy_static! {
+    static ref LINK_REGEX: regex::Regex = regex::Regex::new(r#"\s(?:xlink:)?(?:href|src)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).expect("valid regex");
+    static ref ENTITY_REGEX: regex::Regex = regex::Regex::new(r#"&(?:#[xX]([0-9a-fA-F]+)|#([0-9]+)|(amp|lt|gt|quot|apos));"#).expect("valid regex");
+    static ref ID_REGEX: regex::Regex = regex::Regex::new(r#"\s(?:id|name)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).expect("valid regex");
+}
+
+/// the addresses the site is published at, links to which are internal links
+const SITE_URLS: &[&str] = &["https://blog.hamaluik.ca", "http://blog.hamaluik.ca"];
+
+/// a generated html page
+pub struct Page {
+    /// the page's file, under `docs/`
+    pub path: PathBuf,
+    /// where the page is served from, i.e. `/posts/some-slug/`
+    pub url: String,
+    pub html: String,
+}
+
+/// The generated site: its pages, along with the ids of the elements in every html and svg file
+/// (keyed by their canonical paths) for checking `#fragment` links against
+pub struct Site {
+    pub root: PathBuf,
+    pub pages: Vec<Page>,
+    ids: HashMap<PathBuf, HashSet<String>>,
+}
+
+impl Site {
+    pub fn load<P: AsRef<Path>>(root: P) -> Result<Site, Box<dyn std::error::Error>> {
+        let root = root.as_ref().to_owned();
+        let mut pages: Vec<Page> = Vec::default();
+        let mut ids: HashMap<PathBuf, HashSet<String>> = HashMap::default();
+        for entry in ignore::WalkBuilder::new(&root)
+            .standard_filters(false)
+            .build()
+        {
+            let entry = entry?;
+            let path = entry.path();
+            let ext = path.extension().map(std::ffi::OsStr::to_str).flatten();
+            if !matches!(ext, Some("html") | Some("svg")) {
+                continue;
+            }
+
+            let contents = std::fs::read_to_string(path)?;
+            ids.insert(std::fs::canonicalize(path)?, element_ids(&contents));
+            if ext == Some("html") {
+                let relative = path
+                    .strip_prefix(&root)?
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                let url = format!("/{}", relative.trim_end_matches("index.html"));
+                pages.push(Page {
+                    path: path.to_owned(),
+                    url,
+                    html: contents,
+                });
+            }
+        }
+        pages.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Site { root, pages, ids })
+    }
+
+    /// the ids in an html or svg file, `None` if it isn't one
+    pub fn ids(&self, path: &Path) -> Option<&HashSet<String>> {
+        std::fs::canonicalize(path)
+            .ok()
+            .and_then(|path| self.ids.get(&path))
+    }
+}
+
+/// every `href` and `src` in a page, unescaped
+pub fn links(html: &str) -> Vec<String> {
+    LINK_REGEX
+        .captures_iter(html)
+        .filter_map(|caps| caps.get(1).or_else(|| caps.get(2)))
+        .map(|url| unescape_html(url.as_str()))
+        .collect()
+}
+
+fn element_ids(html: &str) -> HashSet<String> {
+    ID_REGEX
+        .captures_iter(html)
+        .filter_map(|caps| caps.get(1).or_else(|| caps.get(2)))
+        .map(|id| unescape_html(id.as_str()))
+        .collect()
+}
+
+/// decode the entities in an attribute's value, as templates escape `/` to `&#x2F;` and so on
+pub fn unescape_html(src: &str) -> String {
+    ENTITY_REGEX
+        .replace_all(src, |caps: &regex::Captures| {
+            let code = match (caps.get(1), caps.get(2), caps.get(3).map(|n| n.as_str())) {
+                (Some(hex), _, _) => u32::from_str_radix(hex.as_str(), 16).ok(),
+                (_, Some(dec), _) => dec.as_str().parse().ok(),
+                (_, _, Some("amp")) => Some('&' as u32),
+                (_, _, Some("lt")) => Some('<' as u32),
+                (_, _, Some("gt")) => Some('>' as u32),
+                (_, _, Some("quot")) => Some('"' as u32),
+                _ => Some('\'' as u32),
+            };
+            code.and_then(char::from_u32)
+                .map(String::from)
+                .unwrap_or_else(|| caps[0].to_owned())
+        })
+        .into_owned()
+}
+
+/// decode the `%20` style escapes in a url's path or fragment
+pub fn percent_decode(src: &str) -> String {
+    let bytes = src.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match (bytes[i], bytes.get(i + 1..i + 3)) {
+            (b'%', Some(hex)) => std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Whether a link goes somewhere outside of the site, returning the link with the site's own
+/// address stripped off if it doesn't
+pub fn internal_link(url: &str) -> Option<&str> {
+    for site in SITE_URLS {
+        if let Some(path) = url.strip_prefix(site) {
+            if path.is_empty() {
+                return Some("/");
+            } else if path.starts_with(&['/', '#', '?'][..]) {
+                return Some(path);
+            }
+        }
+    }
+    let scheme = url.find(':').map(|i| &url[..i]).filter(|s| {
+        s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    });
+    if scheme.is_some() || url.starts_with("//") {
+        None
+    } else {
+        Some(url)
+    }
+}
+
+/// Where a link on a page came from: the line of the post's source that it is on if the page is
+/// a post, otherwise the page itself
+pub fn locate(page: &Page, link: &str, posts: &[Post]) -> String {
+    let post = match posts.iter().find(|post| post.url == page.url) {
+        Some(post) => post,
+        None => return page.path.display().to_string(),
+    };
+    let needle = internal_link(link).unwrap_or(link);
+    let line = post
+        .contents
+        .lines()
+        .position(|line| line.contains(link) || line.contains(needle));
+    match line {
+        Some(line) => format!("{}:{}", post.source.display(), line + 1 + post.line_offset),
+        None => format!("{} ({})", post.source.display(), page.path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_entities() {
+        assert_eq!(unescape_html("&#x2F;posts&#x2F;a&#47;"), "/posts/a/");
+        assert_eq!(
+            unescape_html("a &amp; b &lt;c&gt; &quot;d&quot; &apos;e&apos;"),
+            r#"a & b <c> "d" 'e'"#
+        );
+        assert_eq!(unescape_html("&amp;amp;"), "&amp;");
+        // anything that isn't a character is left as it is
+        assert_eq!(unescape_html("&#xD800; &nbsp; & ;"), "&#xD800; &nbsp; & ;");
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%20file.png"), "a file.png");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("%2f%2F"), "//");
+        // broken escapes are kept
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn finds_internal_links() {
+        assert_eq!(internal_link("/posts/a/"), Some("/posts/a/"));
+        assert_eq!(internal_link("../b/#top"), Some("../b/#top"));
+        assert_eq!(internal_link("#top"), Some("#top"));
+        assert_eq!(internal_link("?page=2"), Some("?page=2"));
+        assert_eq!(internal_link("https://blog.hamaluik.ca"), Some("/"));
+        assert_eq!(
+            internal_link("https://blog.hamaluik.ca/posts/a/#top"),
+            Some("/posts/a/#top")
+        );
+        assert_eq!(internal_link("https://blog.hamaluik.ca.example.com/"), None);
+        assert_eq!(internal_link("https://example.com/"), None);
+        assert_eq!(internal_link("//example.com/a.png"), None);
+        assert_eq!(internal_link("mailto:me@example.com"), None);
+    }
+}
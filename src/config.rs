@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// fail the build when anything in a post fails to render, instead of rendering the error
+    /// into the page (can also be enabled with `--strict`)
+    pub strict: bool,
+    /// LaTeX macros passed to every KaTeX render, can be extended / overridden per post
+    pub math_macros: BTreeMap<String, String>,
+    /// how to run code blocks marked `test`, keyed on the code block's language
+    pub tests: BTreeMap<String, TestCommand>,
+    /// render footnotes as sidenotes in the margin instead of a list at the end of the post,
+    /// can be overridden per post with `sidenotes` in the front matter
+    pub sidenotes: bool,
+    /// how citations of a post's `bibliography` are written, can be overridden per post with
+    /// `citation_style` in the front matter
+    pub citation_style: CitationStyle,
+    /// how `check --external` checks the links to other sites
+    pub external_links: ExternalLinks,
+    /// how the images under `assets/images/` are resized and re-encoded
+    pub images: Images,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CitationStyle {
+    /// `[1]`, with the references listed in the order they are first cited
+    Numeric,
+    /// `(Hamaluik 2014)`, with the references listed alphabetically
+    AuthorYear,
+}
+
+impl Default for CitationStyle {
+    fn default() -> CitationStyle {
+        CitationStyle::Numeric
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ExternalLinks {
+    /// how many links are checked at once
+    pub concurrency: usize,
+    /// how many seconds to wait on each site before giving up on it
+    pub timeout: u64,
+    /// how many days a link's result is cached for before it is checked again
+    pub cache_days: i64,
+    /// links starting with any of these aren't checked, i.e. sites that turn away bots
+    pub ignore: Vec<String>,
+}
+
+impl Default for ExternalLinks {
+    fn default() -> ExternalLinks {
+        ExternalLinks {
+            concurrency: 8,
+            timeout: 15,
+            cache_days: 30,
+            ignore: Vec::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Images {
+    /// the widths (in pixels) images are resized to, on top of their own width
+    pub widths: Vec<u32>,
+    /// the formats images are re-encoded to, on top of their own format
+    pub formats: Vec<ImageFormat>,
+    /// encoding quality, from 1 to 100
+    pub quality: u8,
+    /// the `sizes` of the generated `srcset`s, i.e. how wide images are shown on the page
+    pub sizes: String,
+    /// show a tiny blurred version of each image in its place until it has loaded
+    pub placeholders: bool,
+}
+
+impl Default for Images {
+    fn default() -> Images {
+        Images {
+            widths: vec![480, 960, 1440],
+            formats: vec![ImageFormat::Avif, ImageFormat::Webp],
+            quality: 80,
+            sizes: "(max-width: 48rem) 100vw, 48rem".to_owned(),
+            placeholders: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageFormat {
+    Avif,
+    Webp,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TestCommand {
+    /// the name of the file the snippet is written to, i.e. `main.rs` or `Main.hx`
+    pub file: String,
+    /// commands run in order from the snippet's directory, with `{file}` and `{dir}` replaced by
+    /// the snippet's path and directory; the last command's stdout is the snippet's output
+    pub commands: Vec<Vec<String>>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(src: P) -> Result<Config, Box<dyn std::error::Error>> {
+        if !src.as_ref().exists() {
+            return Ok(Config::default());
+        }
+        let contents = std::fs::read_to_string(src.as_ref())?;
+        let config: Config = serde_yaml::from_str(&contents)?;
+        Ok(config)
+    }
+}
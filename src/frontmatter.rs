@@ -1,5 +1,7 @@
+use crate::config::CitationStyle;
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Deserialize)]
 pub struct RawFrontMatter {
@@ -9,6 +11,10 @@ pub struct RawFrontMatter {
     pub published: Option<String>,
     pub summary: String,
     pub section: Option<String>,
+    pub math_macros: Option<BTreeMap<String, String>>,
+    pub sidenotes: Option<bool>,
+    pub bibliography: Option<String>,
+    pub citation_style: Option<CitationStyle>,
 }
 
 #[derive(Serialize, Clone)]
@@ -19,6 +25,13 @@ pub struct FrontMatter {
     pub date: DateTime<Utc>,
     pub summary: String,
     pub section: String,
+    pub math_macros: BTreeMap<String, String>,
+    /// overrides the site's `sidenotes` setting for this post
+    pub sidenotes: Option<bool>,
+    /// a BibTeX file, relative to the post, for `[@key]` citations
+    pub bibliography: Option<String>,
+    /// overrides the site's `citation_style` for this post
+    pub citation_style: Option<CitationStyle>,
 }
 
 impl From<RawFrontMatter> for Option<FrontMatter> {
@@ -31,6 +44,10 @@ impl From<RawFrontMatter> for Option<FrontMatter> {
             published,
             summary,
             section,
+            math_macros,
+            sidenotes,
+            bibliography,
+            citation_style,
         } = raw;
         if published.is_none() {
             return None;
@@ -54,6 +71,10 @@ impl From<RawFrontMatter> for Option<FrontMatter> {
             date,
             summary,
             section: section.unwrap_or("Miscellaneous".to_owned()),
+            math_macros: math_macros.unwrap_or_default(),
+            sidenotes,
+            bibliography,
+            citation_style,
         })
     }
 }
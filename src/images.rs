@@ -0,0 +1,540 @@
+//! Responsive images: the jpgs and pngs under `assets/images/` are resized to the configured
+//! widths and re-encoded to AVIF / WebP alongside an optimised copy of the original, for posts to
+//! serve with `<picture>` and `srcset`. Encoding is slow, so every version is cached in `.cache/`.
+use crate::assets::Asset;
+use crate::cache;
+use crate::check::{percent_decode, unescape_html};
+use crate::config::{ImageFormat, Images};
+use image::{DynamicImage, ImageEncoder};
+use std::path::{Path, PathBuf};
+
+lazy_static::lazy_static! {
+    static ref IMG_TAG_REGEX: regex::Regex = regex::Regex::new(r#"<img\b[^>]*>"#).expect("valid regex");
+    static ref SVG_TAG_REGEX: regex::Regex = regex::Regex::new(r#"<svg\b[^>]*>"#).expect("valid regex");
+    /// a quoted, unquoted or boolean attribute
+    static ref ATTRIBUTE_REGEX: regex::Regex = regex::Regex::new(r#"\s([\w:-]+)(?:\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'=<>`]+)))?"#).expect("valid regex");
+}
+
+/// how many pixels across (at most) the placeholders are
+const PLACEHOLDER_SIZE: u32 = 16;
+
+/// one of the versions of an image that the pipeline produces
+pub struct Variant {
+    /// where this version is served from
+    pub url: String,
+    pub width: u32,
+    /// the re-encoded format, `None` for the image's own format
+    pub format: Option<ImageFormat>,
+}
+
+fn extension(url: &str) -> Option<&str> {
+    let name = url.rsplit('/').next()?;
+    name.rfind('.').map(|i| &name[i + 1..])
+}
+
+fn format_extension(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Avif => "avif",
+        ImageFormat::Webp => "webp",
+    }
+}
+
+fn mime_type(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Avif => "image/avif",
+        ImageFormat::Webp => "image/webp",
+    }
+}
+
+/// whether the image served from `url` goes through the pipeline
+pub fn is_processed(url: &str) -> bool {
+    url.starts_with("/images/")
+        && matches!(
+            extension(url).map(str::to_ascii_lowercase).as_deref(),
+            Some("jpg") | Some("jpeg") | Some("png")
+        )
+}
+
+/// the source file of an image served from `url`
+pub fn source_path(url: &str) -> PathBuf {
+    PathBuf::from("assets").join(percent_decode(url.trim_start_matches('/')))
+}
+
+/// Where a version of the image at `url` is served from: `photo-480w.webp` for one resized to
+/// 480 pixels and re-encoded as WebP, `photo.webp` at the image's own width, and `photo.jpg`
+/// itself for the optimised original
+pub fn variant_url(url: &str, width: Option<u32>, format: Option<ImageFormat>) -> String {
+    let ext = extension(url).unwrap_or_default();
+    let stem = url[..url.len() - ext.len()].trim_end_matches('.');
+    let ext = format.map(format_extension).unwrap_or(ext);
+    match width {
+        Some(width) => format!("{}-{}w.{}", stem, width, ext),
+        None => format!("{}.{}", stem, ext),
+    }
+}
+
+/// every version of an image `full_width` pixels wide that is served from `url`: each format at
+/// each of the configured widths that is narrower than the image, and at the image's own width
+pub fn variants(url: &str, full_width: u32, config: &Images) -> Vec<Variant> {
+    let mut widths: Vec<Option<u32>> = config
+        .widths
+        .iter()
+        .filter(|w| **w < full_width)
+        .map(|w| Some(*w))
+        .collect();
+    widths.sort();
+    widths.dedup();
+    widths.push(None);
+
+    let mut formats: Vec<Option<ImageFormat>> = config.formats.iter().copied().map(Some).collect();
+    formats.push(None);
+
+    let mut variants: Vec<Variant> = Vec::default();
+    for format in formats {
+        for width in widths.iter() {
+            variants.push(Variant {
+                url: variant_url(url, *width, format),
+                width: width.unwrap_or(full_width),
+                format,
+            });
+        }
+    }
+    variants
+}
+
+fn encode(
+    image: &DynamicImage,
+    original: &[u8],
+    variant: &Variant,
+    quality: u8,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let full_size = variant.width >= image.width();
+    let image = if !full_size {
+        image.resize(
+            variant.width,
+            u32::MAX,
+            image::imageops::FilterType::Lanczos3,
+        )
+    } else {
+        image.clone()
+    };
+    let is_png = extension(&variant.url)
+        .map(str::to_ascii_lowercase)
+        .as_deref()
+        == Some("png");
+    let mut encoded: Vec<u8> = Vec::default();
+    match variant.format {
+        Some(ImageFormat::Avif) => {
+            let rgba = image.to_rgba8();
+            image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut encoded, 6, quality)
+                .write_image(&rgba, rgba.width(), rgba.height(), image::ColorType::Rgba8)?;
+        }
+        Some(ImageFormat::Webp) => {
+            // the encoder only takes 8 bit rgb(a)
+            let image = DynamicImage::ImageRgba8(image.to_rgba8());
+            let encoder = webp::Encoder::from_image(&image).map_err(|e| e.to_owned())?;
+            encoded.extend_from_slice(&encoder.encode(quality as f32));
+        }
+        None if is_png => {
+            image::codecs::png::PngEncoder::new_with_quality(
+                &mut encoded,
+                image::codecs::png::CompressionType::Best,
+                image::codecs::png::FilterType::Adaptive,
+            )
+            .write_image(
+                image.as_bytes(),
+                image.width(),
+                image.height(),
+                image.color(),
+            )?;
+        }
+        None => {
+            let rgb = image.to_rgb8();
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, quality).write_image(
+                &rgb,
+                rgb.width(),
+                rgb.height(),
+                image::ColorType::Rgb8,
+            )?;
+        }
+    }
+
+    // re-encoding an already well compressed original can make it bigger
+    if variant.format.is_none() && full_size && encoded.len() > original.len() {
+        return Ok(original.to_vec());
+    }
+    Ok(encoded)
+}
+
+/// Write every version of an image asset into `outdir`, encoding the ones that aren't cached yet,
+/// returning the urls they are served from
+pub fn process(
+    asset: &Asset,
+    outdir: &Path,
+    config: &Images,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let original = std::fs::read(&asset.source)?;
+    let image = image::load_from_memory(&original)?;
+    let source_key = cache::hash_key(&original);
+    let variants = variants(&asset.url, image.width(), config);
+    for variant in variants.iter() {
+        let key = format!(
+            "{}:{}:{}:{}",
+            source_key, variant.url, variant.width, config.quality
+        );
+        let encoded = cache::cached_bytes("images", &key, || {
+            encode(&image, &original, variant, config.quality)
+        })?;
+        let dest = outdir.join(variant.url.trim_start_matches('/'));
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(dest, encoded)?;
+    }
+    Ok(variants.into_iter().map(|variant| variant.url).collect())
+}
+
+/// A tiny, blurred version of the image served from `url` as a `data:` url, for showing in its
+/// place until it has loaded. Images with transparency don't get one, as it would show through.
+pub fn placeholder(url: &str) -> Option<String> {
+    if !is_processed(url) {
+        return None;
+    }
+    let original = std::fs::read(source_path(url)).ok()?;
+    let placeholder = cache::cached("placeholders", &cache::hash_key(&original), || {
+        let image = image::load_from_memory(&original)?;
+        if image.color().has_alpha() {
+            return Ok(String::default());
+        }
+        let thumbnail = image
+            .thumbnail(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE)
+            .blur(1.0)
+            .to_rgb8();
+        let mut encoded: Vec<u8> = Vec::default();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, 60).write_image(
+            &thumbnail,
+            thumbnail.width(),
+            thumbnail.height(),
+            image::ColorType::Rgb8,
+        )?;
+        Ok(format!(
+            "data:image/jpeg;base64,{}",
+            base64::encode(&encoded)
+        ))
+    });
+    match placeholder {
+        Ok(placeholder) if !placeholder.is_empty() => Some(placeholder),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("failed to create a placeholder for `{}`: {}", url, e);
+            None
+        }
+    }
+}
+
+fn srcset(variants: &[&Variant]) -> String {
+    variants
+        .iter()
+        .map(|v| format!("{} {}w", v.url, v.width))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Markup for showing the image served from `url`: a `<picture>` offering each of its formats
+/// and widths if it goes through the pipeline, otherwise a plain `<img>`. `attributes` (already
+/// escaped, each with a leading space) go on the `<img>`.
+pub fn picture_html(url: &str, attributes: &str, config: &Images) -> String {
+    let full_width = match image::image_dimensions(source_path(url)) {
+        Ok((width, _)) if is_processed(url) => width,
+        // i.e. images from other sites
+        _ => return format!(r#"<img src="{}"{}>"#, url, attributes),
+    };
+    let variants = variants(url, full_width, config);
+
+    let mut html = String::from("<picture>");
+    for format in config.formats.iter() {
+        let sources: Vec<&Variant> = variants
+            .iter()
+            .filter(|v| v.format == Some(*format))
+            .collect();
+        html.push_str(&format!(
+            r#"<source type="{}" srcset="{}" sizes="{}">"#,
+            mime_type(*format),
+            srcset(&sources),
+            config.sizes
+        ));
+    }
+    let fallbacks: Vec<&Variant> = variants.iter().filter(|v| v.format.is_none()).collect();
+    html.push_str(&format!(
+        r#"<img src="{}" srcset="{}" sizes="{}"{}></picture>"#,
+        url,
+        srcset(&fallbacks),
+        config.sizes,
+        attributes
+    ));
+    html
+}
+
+/// Swap the `<img>`s in some html (i.e. a shortcode's) for the `<picture>`s that `picture_html`
+/// gives the images that go through the pipeline
+pub fn pictures(html: &str, config: &Images) -> String {
+    IMG_TAG_REGEX
+        .replace_all(html, |caps: &regex::Captures| {
+            let tag = &caps[0];
+            let attributes = tag_attributes(tag);
+            // templates escape the `/`s in urls
+            let src = match attributes.iter().find(|(n, _)| n == "src") {
+                Some((_, src)) => unescape_html(src),
+                None => return tag.to_owned(),
+            };
+            if !is_processed(&src) {
+                return tag.to_owned();
+            }
+            let rest: String = attributes
+                .iter()
+                .filter(|(n, _)| n != "src")
+                .map(|(n, v)| format!(r#" {}="{}""#, n, v.replace('"', "&quot;")))
+                .collect();
+            picture_html(&src, &rest, config)
+        })
+        .into_owned()
+}
+
+/// the attributes of an html or svg tag, with their names lowercased and boolean attributes given
+/// an empty value
+fn tag_attributes(tag: &str) -> Vec<(String, String)> {
+    ATTRIBUTE_REGEX
+        .captures_iter(tag)
+        .map(|caps| {
+            let value = caps
+                .get(2)
+                .or_else(|| caps.get(3))
+                .or_else(|| caps.get(4))
+                .map(|v| v.as_str());
+            (
+                caps[1].to_ascii_lowercase(),
+                value.unwrap_or_default().to_owned(),
+            )
+        })
+        .collect()
+}
+
+/// a length in pixels, `None` for other units such as `100%`
+fn parse_length(value: &str) -> Option<f64> {
+    value
+        .trim()
+        .trim_end_matches("px")
+        .parse()
+        .ok()
+        .filter(|length| *length > 0.0)
+}
+
+/// the intrinsic size of an svg from its `width` and `height`, or failing those its `viewBox`
+fn svg_dimensions(svg: &str) -> Option<(u32, u32)> {
+    let attributes = tag_attributes(SVG_TAG_REGEX.find(svg)?.as_str());
+    let get = |name: &str| {
+        attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    };
+    let view_box = get("viewbox").and_then(|view_box| {
+        let numbers: Vec<f64> = view_box
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        match numbers.as_slice() {
+            [_, _, width, height] if *width > 0.0 && *height > 0.0 => Some((*width, *height)),
+            _ => None,
+        }
+    });
+    let width = get("width").and_then(parse_length);
+    let height = get("height").and_then(parse_length);
+    let (width, height) = match (width, height, view_box) {
+        (Some(width), Some(height), _) => (width, height),
+        (Some(width), None, Some((vw, vh))) => (width, width * vh / vw),
+        (None, Some(height), Some((vw, vh))) => (height * vw / vh, height),
+        (_, _, Some(view_box)) => view_box,
+        _ => return None,
+    };
+    Some((width.round() as u32, height.round() as u32))
+}
+
+/// the intrinsic size of the local image or svg served from `url`, as it is written in an html
+/// attribute (i.e. `&#x2F;images&#x2F;my%20photo.jpg` from a template)
+pub fn dimensions(url: &str) -> Option<(u32, u32)> {
+    let url = unescape_html(url);
+    if !url.starts_with('/') || url.starts_with("//") {
+        return None;
+    }
+    let url = url.split(&['?', '#'][..]).next()?;
+    let path = source_path(url);
+    if extension(url).map(str::to_ascii_lowercase).as_deref() == Some("svg") {
+        svg_dimensions(&std::fs::read_to_string(path).ok()?)
+    } else {
+        image::image_dimensions(path).ok()
+    }
+}
+
+/// Give every `<img>` in a page's html its intrinsic `width` and `height` so that the page
+/// doesn't shift around as images load in, and have all but the first image (which is likely to
+/// be on screen straight away) load lazily. Anything already set on an image is left alone.
+pub fn add_image_attributes(html: &str) -> String {
+    let mut first = true;
+    IMG_TAG_REGEX
+        .replace_all(html, |caps: &regex::Captures| {
+            let tag = &caps[0];
+            let attributes = tag_attributes(tag);
+            let has = |name: &str| attributes.iter().any(|(n, _)| n == name);
+
+            let mut added = String::default();
+            if !has("width") && !has("height") {
+                let src = attributes.iter().find(|(n, _)| n == "src");
+                if let Some((width, height)) = src.and_then(|(_, src)| dimensions(src)) {
+                    added.push_str(&format!(r#" width="{}" height="{}""#, width, height));
+                }
+            }
+            if !first {
+                if !has("loading") {
+                    added.push_str(r#" loading="lazy""#);
+                }
+                if !has("decoding") {
+                    added.push_str(r#" decoding="async""#);
+                }
+            }
+            first = false;
+
+            let end = if tag.ends_with("/>") { "/>" } else { ">" };
+            format!(
+                "{}{}{}",
+                tag[..tag.len() - end.len()].trim_end(),
+                added,
+                end
+            )
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variants_are_named_after_their_width_and_format() {
+        let url = "/images/photo.jpg";
+        assert_eq!(variant_url(url, None, None), "/images/photo.jpg");
+        assert_eq!(variant_url(url, Some(480), None), "/images/photo-480w.jpg");
+        assert_eq!(
+            variant_url(url, None, Some(ImageFormat::Avif)),
+            "/images/photo.avif"
+        );
+        assert_eq!(
+            variant_url(url, Some(960), Some(ImageFormat::Webp)),
+            "/images/photo-960w.webp"
+        );
+    }
+
+    #[test]
+    fn variants_only_replace_the_last_extension() {
+        assert_eq!(
+            variant_url("/images/v1.2/diagram.final.png", Some(480), None),
+            "/images/v1.2/diagram.final-480w.png"
+        );
+        assert_eq!(
+            variant_url(
+                "/images/v1.2/diagram.final.png",
+                None,
+                Some(ImageFormat::Webp)
+            ),
+            "/images/v1.2/diagram.final.webp"
+        );
+    }
+
+    #[test]
+    fn images_are_only_resized_down() {
+        let config = Images::default();
+        let urls: Vec<String> = variants("/images/photo.png", 1000, &config)
+            .into_iter()
+            .map(|variant| variant.url)
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "/images/photo-480w.avif",
+                "/images/photo-960w.avif",
+                "/images/photo.avif",
+                "/images/photo-480w.webp",
+                "/images/photo-960w.webp",
+                "/images/photo.webp",
+                "/images/photo-480w.png",
+                "/images/photo-960w.png",
+                "/images/photo.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_images_from_the_pipeline_become_pictures() {
+        let config = Images::default();
+        let external = r#"<img src="https://example.com/photo.jpg" alt="a">"#;
+        assert_eq!(pictures(external, &config), external);
+        // without a source file to read its width from, the image is left as an `<img>`
+        assert_eq!(
+            pictures(
+                r#"<img src="&#x2F;images&#x2F;missing.jpg" alt='say "hi"'>"#,
+                &config
+            ),
+            r#"<img src="/images/missing.jpg" alt="say &quot;hi&quot;">"#
+        );
+        // unquoted and boolean attributes are kept too
+        assert_eq!(
+            pictures(
+                r#"<img src=/images/missing.jpg loading=lazy hidden alt="">"#,
+                &config
+            ),
+            r#"<img src="/images/missing.jpg" loading="lazy" hidden="" alt="">"#
+        );
+    }
+
+    #[test]
+    fn svg_dimensions_come_from_width_and_height() {
+        let svg = r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" width="120px" height='80' viewBox="0 0 12 8">"#;
+        assert_eq!(svg_dimensions(svg), Some((120, 80)));
+    }
+
+    #[test]
+    fn svg_dimensions_fall_back_to_the_view_box() {
+        assert_eq!(
+            svg_dimensions(r#"<svg viewBox="0 0 300.4 150">"#),
+            Some((300, 150))
+        );
+        assert_eq!(
+            svg_dimensions(r#"<svg viewBox="0,0,300,150" width="100%">"#),
+            Some((300, 150))
+        );
+        // a single length is scaled by the view box's aspect ratio
+        assert_eq!(
+            svg_dimensions(r#"<svg width="600" viewBox="0 0 300 150">"#),
+            Some((600, 300))
+        );
+        assert_eq!(
+            svg_dimensions(r#"<svg HEIGHT="50" viewBox="0 0 300 150">"#),
+            Some((100, 50))
+        );
+    }
+
+    #[test]
+    fn svgs_without_a_size_have_no_dimensions() {
+        assert_eq!(svg_dimensions(r#"<svg width="100%" height="100%">"#), None);
+        assert_eq!(svg_dimensions(r#"<svg viewBox="0 0 0 10">"#), None);
+        assert_eq!(svg_dimensions("<html></html>"), None);
+    }
+
+    #[test]
+    fn source_paths_are_decoded() {
+        assert_eq!(
+            source_path("/images/my%20photo.jpg"),
+            Path::new("assets").join("images/my photo.jpg")
+        );
+    }
+}
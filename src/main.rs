@@ -1,8 +1,14 @@
+mod assets;
+mod cache;
+mod check;
+mod config;
 mod frontmatter;
+mod images;
 mod post;
+use config::Config;
 use post::Post;
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::{Path, PathBuf};
 
 fn load_posts<P: AsRef<Path>>(src: P) -> Result<Vec<Post>, Box<dyn std::error::Error>> {
@@ -50,7 +56,57 @@ fn group_posts(
     Ok(map)
 }
 
-fn main() {
+/// the posts linking to each post (keyed by url), from the links in their rendered html
+fn build_backlinks(
+    posts: &[(&Post, post::markdown::FormatResponse)],
+) -> HashMap<String, Vec<post::LinkTarget>> {
+    let mut backlinks: HashMap<String, Vec<post::LinkTarget>> = HashMap::default();
+    for (post, formatted) in posts.iter() {
+        for url in post::linked_posts(&formatted.output) {
+            if url == post.url {
+                continue;
+            }
+            backlinks.entry(url).or_default().push(post::LinkTarget {
+                url: post.url.clone(),
+                title: post.front.title.clone(),
+                slug: post.front.slug.clone(),
+            });
+        }
+    }
+    backlinks
+}
+
+fn test(config: &Config) {
+    use rayon::prelude::*;
+
+    let posts = load_posts("posts").expect("can load posts from posts/ folder");
+    println!("Found {} posts, testing their code blocks...", posts.len());
+    let results: Vec<post::TestResults> = posts
+        .par_iter()
+        .map(|post| match post.test(config) {
+            Ok(results) => results,
+            Err(e) => post::TestResults {
+                passed: 0,
+                failures: vec![format!("failed to test `{}`: {}", post.source.display(), e)],
+            },
+        })
+        .collect();
+
+    let passed: usize = results.iter().map(|r| r.passed).sum();
+    let failures: Vec<&String> = results.iter().flat_map(|r| r.failures.iter()).collect();
+    if failures.len() > 0 {
+        eprintln!("Some code blocks failed:");
+        for failure in failures.iter() {
+            eprintln!("  {}", failure);
+        }
+    }
+    println!("{} code blocks passed, {} failed", passed, failures.len());
+    if failures.len() > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn build(config: &Config) {
     use rayon::prelude::*;
 
     let outdir: PathBuf = PathBuf::from("docs").join("posts");
@@ -63,13 +119,36 @@ fn main() {
 
     let posts = load_posts("posts").expect("can load posts from posts/ folder");
     println!("Found {} posts, rendering them...", posts.len());
-    let errors: Vec<String> = posts
+    let link_targets = Post::link_targets(&posts);
+    let formatted: Vec<Result<(&Post, post::markdown::FormatResponse), String>> = posts
         .par_iter()
-        .filter_map(|post| {
-            let html = match post.render(&style, &katex_style) {
+        .map(|post| {
+            post.format(config, &link_targets)
+                .map(|formatted| (post, formatted))
+                .map_err(|e| format!("failed to render `{}`: {:?}", post.source.display(), e))
+        })
+        .collect();
+    let mut errors: Vec<String> = Vec::default();
+    let mut formatted_posts: Vec<(&Post, post::markdown::FormatResponse)> = Vec::default();
+    for result in formatted {
+        match result {
+            Ok(f) => formatted_posts.push(f),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    let backlinks = build_backlinks(&formatted_posts);
+    let pages: Vec<Result<PathBuf, String>> = formatted_posts
+        .par_iter()
+        .map(|(post, formatted)| {
+            let backlinks = backlinks
+                .get(&post.url)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            let html = match post.render(formatted, backlinks, &style, &katex_style) {
                 Ok(h) => h,
                 Err(e) => {
-                    return Some(format!(
+                    return Err(format!(
                         "failed to render `{}`: {:?}",
                         post.source.display(),
                         e
@@ -79,15 +158,28 @@ fn main() {
             let outdir = outdir.join(&post.front.slug);
             std::fs::create_dir_all(&outdir).expect("can create dir for post");
             let outfile = outdir.join("index.html");
-            std::fs::write(outfile, html).expect("can write post to index.html file");
-            return None;
+            std::fs::write(&outfile, html).expect("can write post to index.html file");
+            Ok(outfile)
         })
         .collect();
+    // the pages written by this build, as opposed to any left in docs/ by earlier ones
+    let mut written: BTreeSet<PathBuf> = BTreeSet::default();
+    for page in pages {
+        match page {
+            Ok(path) => {
+                written.insert(path);
+            }
+            Err(e) => errors.push(e),
+        }
+    }
     if errors.len() > 0 {
         eprintln!("Failed to render some posts:");
         for error in errors.iter() {
             eprintln!("  {}", error);
         }
+        if config.strict {
+            std::process::exit(1);
+        }
     } else {
         println!("Posts rendered!");
     }
@@ -109,7 +201,8 @@ fn main() {
         minifier.set_minify_code(false);
         minifier.digest(rendered).expect("can minify index");
         let outpath = PathBuf::from("docs").join("index.html");
-        std::fs::write(outpath, minifier.get_html()).expect("can write index to index.html file");
+        std::fs::write(&outpath, minifier.get_html()).expect("can write index to index.html file");
+        written.insert(outpath);
     }
     println!("Index generated!");
 
@@ -173,33 +266,122 @@ fn main() {
 
     println!("Copying assets...");
     let outdir = PathBuf::from("docs");
-    let mut paths: Vec<PathBuf> = Vec::default();
-    for entry in ignore::Walk::new("assets") {
-        let entry = entry.expect("can get path entry");
-        if let Some(t) = entry.file_type() {
-            if t.is_file() {
-                if let Some("md") = entry
-                    .path()
-                    .extension()
-                    .map(std::ffi::OsStr::to_str)
-                    .flatten()
-                {
-                    // ignore markdown files
-                } else {
-                    // we found an asset to copy!
-                    paths.push(entry.path().to_owned());
+    let assets = assets::list("assets").expect("can list assets");
+    // the posts already offer every version of the images, so an image that can't be processed
+    // fails the build rather than leaving them pointing at versions that don't exist
+    let processed: Vec<Result<Vec<String>, String>> = assets
+        .par_iter()
+        .map(|asset| {
+            if images::is_processed(&asset.url) {
+                return images::process(asset, &outdir, &config.images).map_err(|e| {
+                    format!(
+                        "failed to process image `{}`: {}",
+                        asset.source.display(),
+                        e
+                    )
+                });
+            }
+            let dest_path: PathBuf = outdir.join(asset.url.trim_start_matches('/'));
+            if let Some(parent) = dest_path.parent() {
+                if !parent.exists() {
+                    std::fs::create_dir_all(parent).expect("can create directory");
                 }
             }
+            if asset.url.ends_with(".svg") {
+                let svg = std::fs::read_to_string(&asset.source).expect("can read svg");
+                std::fs::write(&dest_path, post::svg::optimise(&svg)).expect("can write svg");
+            } else {
+                std::fs::copy(&asset.source, &dest_path).expect("can copy file");
+            }
+            Ok(vec![asset.url.clone()])
+        })
+        .collect();
+    let mut generated: BTreeSet<String> = BTreeSet::default();
+    let mut failed: Vec<String> = Vec::default();
+    for result in processed {
+        match result {
+            Ok(urls) => generated.extend(urls),
+            Err(e) => failed.push(e),
         }
     }
-    paths.par_iter().for_each(|path| {
-        let dest_path: PathBuf =
-            outdir.join(path.iter().skip(1).map(PathBuf::from).collect::<PathBuf>());
-        if let Some(parent) = dest_path.parent() {
-            if !parent.exists() {
-                std::fs::create_dir_all(parent).expect("can create directory");
-            }
+    if failed.len() > 0 {
+        eprintln!("Failed to process some images:");
+        for error in failed.iter() {
+            eprintln!("  {}", error);
         }
-        std::fs::copy(path, &dest_path).expect("can copy file");
-    });
+        std::process::exit(1);
+    }
+
+    println!("Checking asset references...");
+    let mut site = check::Site::load(&outdir).expect("can load the generated site");
+    site.pages.retain(|page| written.contains(&page.path));
+    generated.insert("/feed.rss".to_owned());
+    let (missing, unused) = assets::validate(&assets, &site, &generated);
+    if unused.len() > 0 {
+        println!("Nothing refers to these assets:");
+        for asset in unused.iter() {
+            println!(
+                "  {:>10}  {}",
+                assets::human_size(asset.size),
+                asset.source.display()
+            );
+        }
+        println!(
+            "  {:>10}  in total",
+            assets::human_size(unused.iter().map(|asset| asset.size).sum())
+        );
+    }
+    if missing.len() > 0 {
+        eprintln!("Some files refer to assets that don't exist:");
+        for error in missing.iter() {
+            eprintln!("  {}", error);
+        }
+        std::process::exit(1);
+    }
+}
+
+fn check(config: &Config, external: bool) {
+    let posts = load_posts("posts").expect("can load posts from posts/ folder");
+    let site = check::Site::load("docs").expect("can load the site generated in docs/");
+    println!("Checking the links in {} pages...", site.pages.len());
+    let mut problems = check::check_internal_links(&site, &posts);
+    if external {
+        match check::check_external_links(&site, &posts, &config.external_links) {
+            Ok(external_problems) => problems.extend(external_problems),
+            Err(e) => problems.push(format!("failed to check external links: {}", e)),
+        }
+    }
+    if problems.len() > 0 {
+        eprintln!("Found some broken links:");
+        for problem in problems.iter() {
+            eprintln!("  {}", problem);
+        }
+        std::process::exit(1);
+    }
+    println!("All links lead somewhere!");
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut config = Config::load("config.yml").expect("can load config.yml");
+    if args.iter().any(|arg| arg == "--strict") {
+        config.strict = true;
+    }
+
+    match args
+        .iter()
+        .find(|arg| !arg.starts_with("--"))
+        .map(String::as_str)
+    {
+        None | Some("build") => build(&config),
+        Some("test") => test(&config),
+        Some("check") => check(&config, args.iter().any(|arg| arg == "--external")),
+        Some(command) => {
+            eprintln!(
+                "unknown command `{}`, expected `build`, `test` or `check`",
+                command
+            );
+            std::process::exit(1);
+        }
+    }
 }
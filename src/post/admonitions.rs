@@ -0,0 +1,98 @@
+use super::markdown::{escape_html, merge_text_nodes};
+use comrak::nodes::{Ast, AstNode, NodeValue};
+use comrak::Arena;
+use std::cell::RefCell;
+
+lazy_static::lazy_static! {
+    static ref ALERT_REGEX: regex::Regex = regex::Regex::new(r#"^\[!([A-Za-z]+)\][ \t]*(.*)$"#).expect("valid regex");
+}
+
+/// the kinds of admonition, each of which has a symbol of the same name in `assets/icons.svg`
+const KINDS: &[&str] = &["note", "tip", "important", "warning", "caution"];
+
+pub const ADMONITION_CLOSE: &str = "</aside>";
+
+pub fn is_admonition(kind: &str) -> bool {
+    KINDS.contains(&kind)
+}
+
+/// the opening html of an admonition, up to and including its title, which defaults to the
+/// kind of admonition
+pub fn admonition_open(kind: &str, title: &str) -> String {
+    let title = match title.trim() {
+        "" => {
+            let mut chars = kind.chars();
+            chars
+                .next()
+                .map(|c| c.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        }
+        title => escape_html(title),
+    };
+    format!(
+        r##"<aside class="admonition {kind}"><p class="admonition-title"><svg class="icon" aria-hidden="true"><use href="/icons.svg#{kind}"></use></svg>{title}</p>"##,
+        kind = kind,
+        title = title
+    )
+}
+
+/// Turn GitHub style `> [!NOTE]` blockquotes into admonitions. A title can follow the marker on
+/// the same line: `> [!TIP] Try this instead`. Blockquotes with an unknown kind are left alone.
+pub fn convert_alert_blockquotes<'a>(arena: &'a Arena<AstNode<'a>>, root: &'a AstNode<'a>) {
+    let quotes: Vec<&'a AstNode<'a>> = root
+        .descendants()
+        .filter(|node| matches!(node.data.borrow().value, NodeValue::BlockQuote))
+        .collect();
+    for quote in quotes {
+        let paragraph = match quote.first_child() {
+            Some(p) if matches!(p.data.borrow().value, NodeValue::Paragraph) => p,
+            _ => continue,
+        };
+
+        // the brackets may have been split into text nodes of their own while looking for links
+        merge_text_nodes(paragraph);
+        let marker_node = match paragraph.first_child() {
+            Some(node) => node,
+            None => continue,
+        };
+        let marker = match &marker_node.data.borrow().value {
+            NodeValue::Text(t) => String::from_utf8_lossy(t).into_owned(),
+            _ => continue,
+        };
+        let (kind, title) = match ALERT_REGEX.captures(&marker) {
+            Some(caps) => (caps[1].to_lowercase(), caps[2].to_owned()),
+            None => continue,
+        };
+        if !is_admonition(&kind) {
+            continue;
+        }
+
+        marker_node.detach();
+        if let Some(first) = paragraph.first_child() {
+            if matches!(
+                first.data.borrow().value,
+                NodeValue::SoftBreak | NodeValue::LineBreak
+            ) {
+                first.detach();
+            }
+        }
+        if paragraph.first_child().is_none() {
+            paragraph.detach();
+        }
+
+        // hoist the quote's contents out between the admonition's opening and closing html,
+        // re-using the quote itself for the closing html
+        let open = admonition_open(&kind, &title).into_bytes();
+        quote.insert_before(
+            arena.alloc(comrak::arena_tree::Node::new(RefCell::new(Ast::new(
+                NodeValue::HtmlInline(open),
+            )))),
+        );
+        let children: Vec<&'a AstNode<'a>> = quote.children().collect();
+        for child in children {
+            child.detach();
+            quote.insert_before(child);
+        }
+        quote.data.borrow_mut().value = NodeValue::HtmlInline(ADMONITION_CLOSE.as_bytes().to_vec());
+    }
+}
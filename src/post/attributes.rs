@@ -0,0 +1,370 @@
+use super::codeinfo::split_tokens;
+use super::markdown::escape_html;
+use comrak::nodes::{Ast, AstNode, NodeValue};
+use comrak::{Arena, ComrakOptions};
+use std::cell::RefCell;
+
+// an attribute list is `{#id .class key=value key="quoted value"}`, though comrak's smart
+// punctuation will have curled the quotes
+const ATTRIBUTE: &str =
+    r#"(?:[#.][\w:-]+|[\w-]+=(?:"[^"]*"|\x{201c}[^\x{201d}]*\x{201d}|[^\s"\x{201c}}]+))"#;
+
+lazy_static::lazy_static! {
+    static ref LEADING_REGEX: regex::Regex = regex::Regex::new(&format!(r#"^\{{\s*({a}(?:\s+{a})*)\s*\}}"#, a = ATTRIBUTE)).expect("valid regex");
+    static ref TRAILING_REGEX: regex::Regex = regex::Regex::new(&format!(r#"\s*\{{\s*({a}(?:\s+{a})*)\s*\}}\s*$"#, a = ATTRIBUTE)).expect("valid regex");
+    static ref WHOLE_REGEX: regex::Regex = regex::Regex::new(&format!(r#"^\s*\{{\s*({a}(?:\s+{a})*)\s*\}}\s*$"#, a = ATTRIBUTE)).expect("valid regex");
+    /// a rendered heading marked by `apply_attribute_lists`, along with comrak's anchor
+    static ref HEADING_MARKER_REGEX: regex::Regex = regex::Regex::new(r#"<h([1-6])>(<a [^>]*class="anchor"[^>]*></a>)?<!--attributes:(\d+)-->"#).expect("valid regex");
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Attributes {
+    pub id: Option<String>,
+    pub classes: Vec<String>,
+    pub pairs: Vec<(String, String)>,
+}
+
+impl Attributes {
+    fn parse(src: &str) -> Result<Attributes, Box<dyn std::error::Error>> {
+        let src = src.replace('\u{201c}', "\"").replace('\u{201d}', "\"");
+        let mut attributes = Attributes::default();
+        for token in split_tokens(&src)? {
+            if let Some(id) = token.strip_prefix('#') {
+                attributes.id = Some(id.to_owned());
+            } else if let Some(class) = token.strip_prefix('.') {
+                attributes.classes.push(class.to_owned());
+            } else if let Some(i) = token.find('=') {
+                attributes
+                    .pairs
+                    .push((token[..i].to_owned(), token[i + 1..].to_owned()));
+            }
+        }
+        Ok(attributes)
+    }
+
+    pub fn id_html(&self) -> String {
+        match &self.id {
+            Some(id) => format!(r#" id="{}""#, escape_html(id)),
+            None => String::default(),
+        }
+    }
+
+    /// the classes and key / value pairs as html attributes, without the id
+    pub fn rest_html(&self) -> String {
+        let mut html = String::default();
+        if !self.classes.is_empty() {
+            html.push_str(&format!(
+                r#" class="{}""#,
+                escape_html(&self.classes.join(" "))
+            ));
+        }
+        for (key, value) in self.pairs.iter() {
+            html.push_str(&format!(r#" {}="{}""#, key, escape_html(value)));
+        }
+        html
+    }
+
+    /// add the attributes to the first tag in `html`, merging classes with any existing ones
+    pub fn inject(&self, html: &str) -> String {
+        let start = match html.find('<') {
+            Some(s) => s,
+            None => return html.to_owned(),
+        };
+        let tag_end = match html[start..].find('>') {
+            Some(e) => start + e,
+            None => return html.to_owned(),
+        };
+        let name_end = html[start + 1..tag_end]
+            .find(|c: char| c.is_whitespace() || c == '/')
+            .map(|i| start + 1 + i)
+            .unwrap_or(tag_end);
+
+        let mut tag = html[name_end..tag_end].to_owned();
+        let mut extra = self.id_html();
+        if !self.classes.is_empty() {
+            let classes = escape_html(&self.classes.join(" "));
+            match tag.find(r#"class=""#) {
+                Some(i) => tag.insert_str(i + 7, &format!("{} ", classes)),
+                None => extra.push_str(&format!(r#" class="{}""#, classes)),
+            }
+        }
+        for (key, value) in self.pairs.iter() {
+            extra.push_str(&format!(r#" {}="{}""#, key, escape_html(value)));
+        }
+
+        format!("{}{}{}{}", &html[..name_end], extra, tag, &html[tag_end..])
+    }
+}
+
+fn text_of<'a>(node: &'a AstNode<'a>) -> Option<String> {
+    match &node.data.borrow().value {
+        NodeValue::Text(t) => Some(String::from_utf8_lossy(t).into_owned()),
+        _ => None,
+    }
+}
+
+fn set_text<'a>(node: &'a AstNode<'a>, text: &str) {
+    if text.is_empty() {
+        node.detach();
+    } else {
+        node.data.borrow_mut().value = NodeValue::Text(text.as_bytes().to_vec());
+    }
+}
+
+/// Find the `{#id .class key=value}` attribute lists in the document, removing them from the
+/// text and returning them along with the node they belong to:
+///
+/// * immediately after an image or link: `![alt](src){.white}`, `[text](url){target=_blank}`
+/// * at the end of a heading: `## Introduction {#intro}`
+/// * at the end of a paragraph, on its own line: `some text\n{.lead}`
+/// * as a paragraph of its own, applying to the preceding block (i.e. a table)
+pub fn collect_attribute_lists<'a>(
+    root: &'a AstNode<'a>,
+) -> Result<Vec<(&'a AstNode<'a>, Attributes)>, Box<dyn std::error::Error>> {
+    let mut lists: Vec<(&'a AstNode<'a>, Attributes)> = Vec::default();
+    let nodes: Vec<&'a AstNode<'a>> = root.descendants().collect();
+    for node in nodes {
+        let text = match text_of(node) {
+            Some(t) => t,
+            None => continue,
+        };
+        let parent = match node.parent() {
+            Some(p) => p,
+            None => continue,
+        };
+
+        // directly following an image or link
+        if let Some(previous) = node.previous_sibling() {
+            let is_link = matches!(
+                previous.data.borrow().value,
+                NodeValue::Image(_) | NodeValue::Link(_)
+            );
+            if is_link {
+                if let Some(caps) = LEADING_REGEX.captures(&text) {
+                    let attributes = Attributes::parse(&caps[1])?;
+                    let rest = text[caps.get(0).expect("match").end()..].to_owned();
+                    set_text(node, &rest);
+                    lists.push((previous, attributes));
+                    continue;
+                }
+            }
+        }
+
+        if node.next_sibling().is_some() {
+            continue;
+        }
+        let is_heading = matches!(parent.data.borrow().value, NodeValue::Heading(_));
+        let is_paragraph = matches!(parent.data.borrow().value, NodeValue::Paragraph);
+
+        // a paragraph that is nothing but an attribute list applies to the block before it
+        if is_paragraph && node.previous_sibling().is_none() {
+            if let Some(caps) = WHOLE_REGEX.captures(&text) {
+                if let Some(block) = parent.previous_sibling() {
+                    lists.push((block, Attributes::parse(&caps[1])?));
+                    parent.detach();
+                    continue;
+                }
+            }
+        }
+
+        // at the end of a heading, or on the last line of a paragraph
+        let on_own_line = node
+            .previous_sibling()
+            .map(|p| matches!(p.data.borrow().value, NodeValue::SoftBreak))
+            .unwrap_or(false);
+        if is_heading || (is_paragraph && on_own_line) {
+            if let Some(caps) = TRAILING_REGEX.captures(&text) {
+                let attributes = Attributes::parse(&caps[1])?;
+                let rest = text[..caps.get(0).expect("match").start()].to_owned();
+                set_text(node, &rest);
+                if is_paragraph {
+                    if let Some(softbreak) = parent.last_child() {
+                        if matches!(softbreak.data.borrow().value, NodeValue::SoftBreak) {
+                            softbreak.detach();
+                        }
+                    }
+                }
+                lists.push((parent, attributes));
+            }
+        }
+    }
+    Ok(lists)
+}
+
+/// render the nodes that have attribute lists (that haven't already been dealt with) into html
+/// with the attributes added to their outermost tag. Headings are only marked, so that comrak
+/// gives them unique anchors when rendering the whole document, and have their attributes added
+/// afterwards by `inject_heading_attributes`.
+pub fn apply_attribute_lists<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    lists: &[(&'a AstNode<'a>, Attributes)],
+    options: &ComrakOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // innermost first, so that the outer nodes include their children's attributes
+    for (i, (node, attributes)) in lists.iter().enumerate().rev() {
+        if node.parent().is_none() {
+            continue;
+        }
+        if matches!(node.data.borrow().value, NodeValue::Heading(_)) {
+            let marker = format!("<!--attributes:{}-->", i).into_bytes();
+            let marker = arena.alloc(comrak::arena_tree::Node::new(RefCell::new(Ast::new(
+                NodeValue::HtmlInline(marker),
+            ))));
+            node.prepend(marker);
+            continue;
+        }
+
+        let existing = match &node.data.borrow().value {
+            NodeValue::HtmlInline(html) => Some(String::from_utf8_lossy(html).into_owned()),
+            NodeValue::HtmlBlock(block) => {
+                Some(String::from_utf8_lossy(&block.literal).into_owned())
+            }
+            _ => None,
+        };
+        let html = match existing {
+            Some(html) => html,
+            None => {
+                let mut html: Vec<u8> = Vec::default();
+                comrak::format_html(node, options, &mut html)?;
+                String::from_utf8(html)?
+            }
+        };
+        let html = attributes.inject(&html).into_bytes();
+
+        let children: Vec<&'a AstNode<'a>> = node.children().collect();
+        for child in children {
+            child.detach();
+        }
+        node.data.borrow_mut().value = NodeValue::HtmlInline(html);
+    }
+    Ok(())
+}
+
+/// add the attributes to the headings marked by `apply_attribute_lists`, swapping comrak's anchor
+/// for the heading's own id when it has one
+pub fn inject_heading_attributes(html: &str, lists: &[(&AstNode, Attributes)]) -> String {
+    HEADING_MARKER_REGEX
+        .replace_all(html, |caps: &regex::Captures| {
+            let tag = format!("<h{}>", &caps[1]);
+            let anchor = caps.get(2).map_or("", |anchor| anchor.as_str());
+            match caps[3].parse().ok().and_then(|i: usize| lists.get(i)) {
+                Some((_, attributes)) if attributes.id.is_some() => attributes.inject(&tag),
+                Some((_, attributes)) => attributes.inject(&tag) + anchor,
+                None => tag + anchor,
+            }
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ids_classes_and_pairs() {
+        let attributes =
+            Attributes::parse(r#"#intro .lead .wide data-x=1 title="two words""#).unwrap();
+        assert_eq!(attributes.id.as_deref(), Some("intro"));
+        assert_eq!(attributes.classes, vec!["lead", "wide"]);
+        assert_eq!(
+            attributes.pairs,
+            vec![
+                ("data-x".to_owned(), "1".to_owned()),
+                ("title".to_owned(), "two words".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_curled_quotes() {
+        let attributes = Attributes::parse("title=\u{201c}two words\u{201d}").unwrap();
+        assert_eq!(
+            attributes.pairs,
+            vec![("title".to_owned(), "two words".to_owned())]
+        );
+    }
+
+    #[test]
+    fn unterminated_quotes_are_errors() {
+        assert!(Attributes::parse(r#"title="two words"#).is_err());
+    }
+
+    #[test]
+    fn finds_attribute_lists() {
+        assert_eq!(
+            &LEADING_REGEX.captures("{.white} and more").unwrap()[1],
+            ".white"
+        );
+        assert!(LEADING_REGEX.captures(" {.white}").is_none());
+        assert_eq!(
+            &TRAILING_REGEX.captures("Introduction {#intro}").unwrap()[1],
+            "#intro"
+        );
+        assert_eq!(
+            &WHOLE_REGEX
+                .captures(r#" {#t1 .wide caption="A table"} "#)
+                .unwrap()[1],
+            r#"#t1 .wide caption="A table""#
+        );
+        // code and set notation in prose aren't attribute lists
+        assert!(WHOLE_REGEX.captures("{x, y}").is_none());
+        assert!(TRAILING_REGEX.captures("the set {1 2 3}").is_none());
+    }
+
+    #[test]
+    fn injects_into_the_first_tag() {
+        let attributes = Attributes::parse(r#"#fig .white loading=eager"#).unwrap();
+        assert_eq!(
+            attributes.inject(r#"<img src="a.png" alt="A"><span>after</span>"#),
+            r#"<img id="fig" class="white" loading="eager" src="a.png" alt="A"><span>after</span>"#
+        );
+        assert_eq!(
+            attributes.inject("<br/>"),
+            r#"<br id="fig" class="white" loading="eager"/>"#
+        );
+    }
+
+    #[test]
+    fn merges_classes_with_existing_ones() {
+        let attributes = Attributes::parse(".wide").unwrap();
+        assert_eq!(
+            attributes.inject(r#"<table class="data"><tr></tr></table>"#),
+            r#"<table class="wide data"><tr></tr></table>"#
+        );
+    }
+
+    #[test]
+    fn headings_keep_unique_anchors_or_take_their_own_id() {
+        let mut options = ComrakOptions::default();
+        options.extension.header_ids = Some("header-".to_owned());
+        options.render.unsafe_ = true;
+        let arena = Arena::new();
+        let root = comrak::parse_document(
+            &arena,
+            "## Intro {#intro}\n\n## Same\n\n## Same {.wide}\n",
+            &options,
+        );
+        let lists = collect_attribute_lists(root).unwrap();
+        apply_attribute_lists(&arena, &lists, &options).unwrap();
+        let mut html: Vec<u8> = Vec::default();
+        comrak::format_html(root, &options, &mut html).unwrap();
+        let html = inject_heading_attributes(&String::from_utf8(html).unwrap(), &lists);
+
+        assert!(html.contains(r#"<h2 id="intro">Intro</h2>"#), "{}", html);
+        assert!(!html.contains("header-intro"), "{}", html);
+        assert!(html.contains(r#"id="header-same""#), "{}", html);
+        assert!(
+            html.contains(r#"<h2 class="wide"><a href="#same-1""#),
+            "{}",
+            html
+        );
+        assert!(!html.contains("<!--"), "{}", html);
+    }
+
+    #[test]
+    fn escapes_values() {
+        let attributes = Attributes::parse(r#"title="<b>""#).unwrap();
+        assert_eq!(attributes.rest_html(), r#" title="&lt;b&gt;""#);
+        assert_eq!(attributes.inject("no tags"), "no tags");
+    }
+}
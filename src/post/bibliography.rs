@@ -0,0 +1,668 @@
+use super::markdown::escape_html;
+use crate::config::CitationStyle;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+lazy_static::lazy_static! {
+    /// `[@key]`, `[@key, p. 33]` or `[@one; @two]`
+    pub static ref CITATION_REGEX: regex::Regex = regex::Regex::new(r#"\[(\s*@[^\]]*)\]"#).expect("valid regex");
+    static ref CITE_REGEX: regex::Regex = regex::Regex::new(r#"^\s*@([^\s,;]+)\s*(?:,\s*(.*?))?\s*$"#).expect("valid regex");
+    static ref ACCENT_REGEX: regex::Regex = regex::Regex::new(r#"\\(["'`^~=.])\s*\{?([A-Za-z])\}?"#).expect("valid regex");
+    static ref COMMAND_REGEX: regex::Regex = regex::Regex::new(r#"\\[A-Za-z]+\s*"#).expect("valid regex");
+    static ref WHITESPACE_REGEX: regex::Regex = regex::Regex::new(r#"\s+"#).expect("valid regex");
+}
+
+/// turn a raw BibTeX value into plain text: braces are dropped, accents are turned into their
+/// combining characters and any other LaTeX commands are stripped
+fn clean(raw: &str) -> String {
+    let text = ACCENT_REGEX.replace_all(raw, |caps: &regex::Captures| {
+        let accent = match &caps[1] {
+            "\"" => '\u{308}',
+            "'" => '\u{301}',
+            "`" => '\u{300}',
+            "^" => '\u{302}',
+            "~" => '\u{303}',
+            "=" => '\u{304}',
+            _ => '\u{307}',
+        };
+        format!("{}{}", &caps[2], accent)
+    });
+    let text = text
+        .replace(r"\&", "&")
+        .replace(r"\%", "%")
+        .replace(r"\_", "_")
+        .replace(r"\$", "$")
+        .replace("---", "\u{2014}")
+        .replace("--", "\u{2013}")
+        .replace('~', "\u{a0}");
+    let text = COMMAND_REGEX.replace_all(&text, "");
+    let text: String = text.chars().filter(|c| *c != '{' && *c != '}').collect();
+    WHITESPACE_REGEX.replace_all(text.trim(), " ").into_owned()
+}
+
+struct Name {
+    first: String,
+    last: String,
+}
+
+impl Name {
+    /// parse `Last, First`, `First Last` or `{Some Organisation}`
+    fn parse(raw: &str) -> Name {
+        let raw = raw.trim();
+        if raw.starts_with('{') && raw.ends_with('}') {
+            return Name {
+                first: String::default(),
+                last: clean(raw),
+            };
+        }
+        let parts = split_top_level(raw, |s| s.strip_prefix(','));
+        match parts.as_slice() {
+            [last, first] | [last, _, first] => Name {
+                first: clean(first),
+                last: clean(last),
+            },
+            _ => {
+                let words = split_top_level(raw, |s| s.strip_prefix(|c: char| c.is_whitespace()));
+                let words: Vec<&str> = words.into_iter().filter(|w| !w.is_empty()).collect();
+                match words.split_last() {
+                    Some((last, first)) => Name {
+                        first: clean(&first.join(" ")),
+                        last: clean(last),
+                    },
+                    None => Name {
+                        first: String::default(),
+                        last: String::default(),
+                    },
+                }
+            }
+        }
+    }
+
+    fn full(&self) -> String {
+        if self.first.is_empty() {
+            self.last.clone()
+        } else {
+            format!("{} {}", self.first, self.last)
+        }
+    }
+}
+
+/// split `src` wherever `separator` matches outside of braces, `separator` returning what is
+/// left after the separator
+fn split_top_level<'s, F>(src: &'s str, separator: F) -> Vec<&'s str>
+where
+    F: Fn(&'s str) -> Option<&'s str>,
+{
+    let mut parts: Vec<&'s str> = Vec::default();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < src.len() {
+        let rest = &src[i..];
+        if depth == 0 {
+            if let Some(after) = separator(rest) {
+                parts.push(&src[start..i]);
+                i = src.len() - after.len();
+                start = i;
+                continue;
+            }
+        }
+        let c = rest.chars().next().expect("not at the end");
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    parts.push(&src[start..]);
+    parts
+}
+
+struct Entry {
+    key: String,
+    kind: String,
+    fields: HashMap<String, String>,
+}
+
+impl Entry {
+    fn get(&self, field: &str) -> Option<String> {
+        self.fields.get(field).map(|v| clean(v))
+    }
+
+    fn names(&self) -> Vec<Name> {
+        let raw = match self
+            .fields
+            .get("author")
+            .or_else(|| self.fields.get("editor"))
+        {
+            Some(raw) => raw,
+            None => return Vec::default(),
+        };
+        split_top_level(raw, |s| {
+            let trimmed = s.trim_start();
+            if trimmed.len() < s.len() {
+                trimmed
+                    .strip_prefix("and")
+                    .filter(|after| after.starts_with(char::is_whitespace))
+            } else {
+                None
+            }
+        })
+        .into_iter()
+        .map(Name::parse)
+        .collect()
+    }
+
+    fn year(&self) -> String {
+        self.get("year")
+            .or_else(|| self.get("date").map(|d| d.chars().take(4).collect()))
+            .unwrap_or_else(|| "n.d.".to_owned())
+    }
+
+    /// `Hamaluik`, `Hamaluik and Smith` or `Hamaluik et al.` for citing in the text
+    fn short_authors(&self) -> String {
+        let names = self.names();
+        match names.as_slice() {
+            [] => self.get("title").unwrap_or_else(|| self.key.clone()),
+            [one] => one.last.clone(),
+            [one, two] => format!("{} and {}", one.last, two.last),
+            [one, ..] => format!("{} et al.", one.last),
+        }
+    }
+
+    fn sort_key(&self) -> (String, String) {
+        (self.short_authors().to_lowercase(), self.year())
+    }
+
+    /// the entry as it is listed in the references
+    fn render(&self) -> String {
+        let mut html = String::default();
+        let names: Vec<String> = self.names().iter().map(Name::full).collect();
+        let authors = match names.split_last() {
+            None => String::default(),
+            Some((last, [])) => last.clone(),
+            Some((last, [first])) => format!("{} and {}", first, last),
+            Some((last, rest)) => format!("{}, and {}", rest.join(", "), last),
+        };
+        if !authors.is_empty() {
+            html.push_str(&escape_html(&authors));
+            html.push(' ');
+        }
+        html.push_str(&format!("({}). ", escape_html(&self.year())));
+
+        let container = self.get("journal").or_else(|| self.get("booktitle"));
+        if let Some(title) = self.get("title") {
+            if container.is_some() {
+                html.push_str(&format!("{}. ", escape_html(&title)));
+            } else {
+                html.push_str(&format!("<em>{}</em>. ", escape_html(&title)));
+            }
+        }
+        if let Some(container) = container {
+            html.push_str(&format!("<em>{}</em>", escape_html(&container)));
+            if let Some(volume) = self.get("volume") {
+                html.push_str(&format!(", {}", escape_html(&volume)));
+                if let Some(number) = self.get("number") {
+                    html.push_str(&format!("({})", escape_html(&number)));
+                }
+            }
+            if let Some(pages) = self.get("pages") {
+                html.push_str(&format!(", {}", escape_html(&pages)));
+            }
+            html.push_str(". ");
+        }
+        let thesis = match self.kind.as_str() {
+            "mastersthesis" => Some("Master's thesis"),
+            "phdthesis" => Some("PhD thesis"),
+            _ => None,
+        };
+        let publisher = self
+            .get("publisher")
+            .or_else(|| self.get("school"))
+            .or_else(|| self.get("institution"));
+        match (thesis, publisher) {
+            (Some(thesis), Some(publisher)) => {
+                html.push_str(&format!("{}, {}. ", thesis, escape_html(&publisher)))
+            }
+            (Some(thesis), None) => html.push_str(&format!("{}. ", thesis)),
+            (None, Some(publisher)) => html.push_str(&format!("{}. ", escape_html(&publisher))),
+            (None, None) => {}
+        }
+        if let Some(doi) = self.get("doi") {
+            html.push_str(&format!(
+                r#"<a href="https://doi.org/{}">doi:{}</a>"#,
+                escape_html(&doi),
+                escape_html(&doi)
+            ));
+        } else if let Some(url) = self.get("url") {
+            html.push_str(&format!(
+                r#"<a href="{}">{}</a>"#,
+                escape_html(&url),
+                escape_html(&url)
+            ));
+        }
+        html.trim_end().to_owned()
+    }
+}
+
+struct Parser<'s> {
+    src: &'s str,
+    pos: usize,
+    strings: HashMap<String, String>,
+}
+
+impl<'s> Parser<'s> {
+    fn error(&self, message: &str) -> String {
+        let line = self.src[..self.pos].matches('\n').count() + 1;
+        format!("line {}: {}", line, message)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(p) if p == c => {
+                self.pos += c.len_utf8();
+                Ok(())
+            }
+            Some(p) => Err(self.error(&format!("expected `{}`, found `{}`", c, p))),
+            None => Err(self.error(&format!("expected `{}`, found the end of the file", c))),
+        }
+    }
+
+    fn identifier(&mut self) -> String {
+        self.skip_whitespace();
+        let rest = &self.src[self.pos..];
+        let end = rest
+            .find(|c: char| c.is_whitespace() || "{}(),=#\"".contains(c))
+            .unwrap_or(rest.len());
+        self.pos += end;
+        rest[..end].to_owned()
+    }
+
+    /// the contents of a `{...}`, `(...)` or `"..."` delimited value, with the opening delimiter
+    /// consumed
+    fn delimited(&mut self, close: char) -> Result<String, String> {
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+            match c {
+                c if c == close && depth == 0 => {
+                    return Ok(self.src[start..self.pos - c.len_utf8()].to_owned())
+                }
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                '\\' => {
+                    // skip escaped characters such as `\"`
+                    if let Some(next) = self.peek() {
+                        self.pos += next.len_utf8();
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.pos = start;
+        Err(self.error(&format!(
+            "unterminated value, missing a closing `{}`",
+            close
+        )))
+    }
+
+    /// a field's value: braced / quoted strings, numbers and `@string` names joined with `#`
+    fn value(&mut self) -> Result<String, String> {
+        let mut value = String::default();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some('{') => {
+                    self.pos += 1;
+                    value.push_str(&self.delimited('}')?);
+                }
+                Some('"') => {
+                    self.pos += 1;
+                    value.push_str(&self.delimited('"')?);
+                }
+                Some(_) => {
+                    let name = self.identifier();
+                    if name.is_empty() {
+                        return Err(self.error("expected a value"));
+                    }
+                    match self.strings.get(&name.to_lowercase()) {
+                        Some(s) => value.push_str(s),
+                        None if name.chars().all(|c| c.is_ascii_digit()) => value.push_str(&name),
+                        None => return Err(self.error(&format!("unknown @string `{}`", name))),
+                    }
+                }
+                None => return Err(self.error("expected a value, found the end of the file")),
+            }
+            self.skip_whitespace();
+            if self.peek() == Some('#') {
+                self.pos += 1;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// where the next entry starts: anything outside of an entry is a comment, so only an `@` at
+    /// the start of a line starts an entry
+    fn next_entry(&self) -> Option<usize> {
+        let mut pos = self.pos;
+        while let Some(at) = self.src[pos..].find('@') {
+            let at = pos + at;
+            let line_start = self.src[..at].rfind('\n').map(|i| i + 1).unwrap_or(0);
+            if self.src[line_start..at].trim().is_empty() {
+                return Some(at);
+            }
+            pos = at + 1;
+        }
+        None
+    }
+
+    fn entries(&mut self) -> Result<BTreeMap<String, Entry>, String> {
+        let mut entries: BTreeMap<String, Entry> = BTreeMap::default();
+        while let Some(at) = self.next_entry() {
+            self.pos = at + 1;
+            let kind = self.identifier().to_lowercase();
+            self.skip_whitespace();
+            let close = match self.peek() {
+                Some('{') => '}',
+                Some('(') => ')',
+                _ => return Err(self.error(&format!("expected `{{` after `@{}`", kind))),
+            };
+            self.pos += 1;
+
+            match kind.as_str() {
+                "comment" => {
+                    self.delimited(close)?;
+                    continue;
+                }
+                "preamble" => {
+                    self.value()?;
+                }
+                "string" => {
+                    let name = self.identifier().to_lowercase();
+                    self.expect('=')?;
+                    let value = self.value()?;
+                    self.strings.insert(name, value);
+                }
+                _ => {
+                    let key = self.identifier();
+                    if key.is_empty() {
+                        return Err(self.error(&format!("`@{}` entry without a key", kind)));
+                    }
+                    let mut fields: HashMap<String, String> = HashMap::default();
+                    loop {
+                        self.skip_whitespace();
+                        match self.peek() {
+                            Some(',') => self.pos += 1,
+                            Some(c) if c == close => break,
+                            _ => {
+                                return Err(self.error(&format!(
+                                    "expected `,` or `{}` in entry `{}`",
+                                    close, key
+                                )))
+                            }
+                        }
+                        self.skip_whitespace();
+                        if self.peek() == Some(close) {
+                            break;
+                        }
+                        let field = self.identifier().to_lowercase();
+                        self.expect('=')?;
+                        let value = self.value()?;
+                        fields.insert(field, value);
+                    }
+                    if entries.contains_key(&key) {
+                        return Err(self.error(&format!("entry `{}` is defined twice", key)));
+                    }
+                    entries.insert(key.clone(), Entry { key, kind, fields });
+                }
+            }
+            self.expect(close)?;
+        }
+        Ok(entries)
+    }
+}
+
+/// A post's bibliography, keeping track of what has been cited so far
+pub struct Bibliography {
+    entries: BTreeMap<String, Entry>,
+    style: CitationStyle,
+    /// keys in the order they were first cited
+    cited: Vec<String>,
+}
+
+impl Bibliography {
+    pub fn load(
+        path: &Path,
+        style: CitationStyle,
+    ) -> Result<Bibliography, Box<dyn std::error::Error>> {
+        let src = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read bibliography `{}`: {}", path.display(), e))?;
+        // the month macros every BibTeX style predefines
+        let strings: HashMap<String, String> = [
+            "January",
+            "February",
+            "March",
+            "April",
+            "May",
+            "June",
+            "July",
+            "August",
+            "September",
+            "October",
+            "November",
+            "December",
+        ]
+        .iter()
+        .map(|month| (month[..3].to_lowercase(), month.to_string()))
+        .collect();
+        let mut parser = Parser {
+            src: &src,
+            pos: 0,
+            strings,
+        };
+        let entries = parser
+            .entries()
+            .map_err(|e| format!("failed to parse bibliography `{}`: {}", path.display(), e))?;
+        Ok(Bibliography {
+            entries,
+            style,
+            cited: Vec::default(),
+        })
+    }
+
+    /// turn a `[@key]` match from the prose into a citation linked to its reference
+    pub fn cite(&mut self, caps: &regex::Captures) -> Result<String, Box<dyn std::error::Error>> {
+        let mut cites: Vec<(&Entry, Option<String>)> = Vec::default();
+        for part in caps[1].split(';') {
+            let cite = CITE_REGEX
+                .captures(part)
+                .ok_or_else(|| format!("can't parse the citation `{}`", part.trim()))?;
+            let entry = self
+                .entries
+                .get(&cite[1])
+                .ok_or_else(|| format!("`{}` isn't in the bibliography", &cite[1]))?;
+            if !self.cited.contains(&entry.key) {
+                self.cited.push(entry.key.clone());
+            }
+            let locator = cite.get(2).map(|l| l.as_str()).filter(|l| !l.is_empty());
+            cites.push((entry, locator.map(str::to_owned)));
+        }
+
+        let has_locators = cites.iter().any(|(_, locator)| locator.is_some());
+        let cites: Vec<String> = cites
+            .iter()
+            .map(|(entry, locator)| {
+                let text = match self.style {
+                    CitationStyle::Numeric => {
+                        let number = self
+                            .cited
+                            .iter()
+                            .position(|k| *k == entry.key)
+                            .expect("cited above")
+                            + 1;
+                        number.to_string()
+                    }
+                    CitationStyle::AuthorYear => {
+                        format!("{} {}", entry.short_authors(), entry.year())
+                    }
+                };
+                let link = format!(
+                    r##"<a href="#ref-{}">{}</a>"##,
+                    escape_html(&entry.key),
+                    escape_html(&text)
+                );
+                match locator {
+                    Some(locator) => format!("{}, {}", link, escape_html(locator)),
+                    None => link,
+                }
+            })
+            .collect();
+        let separator = match (self.style, has_locators) {
+            (CitationStyle::Numeric, false) => ", ",
+            _ => "; ",
+        };
+        let (open, close) = match self.style {
+            CitationStyle::Numeric => ("[", "]"),
+            CitationStyle::AuthorYear => ("(", ")"),
+        };
+        Ok(format!(
+            r#"<span class="citation">{}{}{}</span>"#,
+            open,
+            cites.join(separator),
+            close
+        ))
+    }
+
+    /// the references section listing everything that was cited, if anything was
+    pub fn references_html(&self) -> Option<String> {
+        if self.cited.is_empty() {
+            return None;
+        }
+        let mut entries: Vec<&Entry> = self
+            .cited
+            .iter()
+            .filter_map(|key| self.entries.get(key))
+            .collect();
+        let list = match self.style {
+            CitationStyle::Numeric => "ol",
+            CitationStyle::AuthorYear => {
+                entries.sort_by_key(|entry| entry.sort_key());
+                "ul"
+            }
+        };
+        let items: String = entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    r#"<li id="ref-{}">{}</li>"#,
+                    escape_html(&entry.key),
+                    entry.render()
+                )
+            })
+            .collect();
+        Some(format!(
+            r#"<section class="references"><h2 id="references">References</h2><{} class="references">{}</{}></section>"#,
+            list, items, list
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(src: &str) -> Result<BTreeMap<String, Entry>, String> {
+        let mut parser = Parser {
+            src,
+            pos: 0,
+            strings: HashMap::default(),
+        };
+        parser.entries()
+    }
+
+    fn parse_error(src: &str) -> String {
+        parse(src).err().expect("the bibliography is malformed")
+    }
+
+    fn field(entries: &BTreeMap<String, Entry>, key: &str, field: &str) -> String {
+        entries[key].fields[field].clone()
+    }
+
+    #[test]
+    fn parses_braced_and_quoted_values() {
+        let entries = parse(
+            r#"@article{knuth,
+                title = {The {\TeX}book},
+                author = "Donald E. Knuth and {Some Organisation}",
+                note = "a \"quoted\" word",
+                year = 1984,
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(entries["knuth"].kind, "article");
+        assert_eq!(field(&entries, "knuth", "title"), r"The {\TeX}book");
+        assert_eq!(
+            field(&entries, "knuth", "author"),
+            "Donald E. Knuth and {Some Organisation}"
+        );
+        assert_eq!(field(&entries, "knuth", "note"), r#"a \"quoted\" word"#);
+        assert_eq!(field(&entries, "knuth", "year"), "1984");
+    }
+
+    #[test]
+    fn expands_strings_and_concatenation() {
+        let entries = parse(
+            r#"@string{acm = "ACM"}
+            @STRING(pub = {Press})
+            @book(key,
+                publisher = acm # " " # pub,
+            )"#,
+        )
+        .unwrap();
+        assert_eq!(field(&entries, "key", "publisher"), "ACM Press");
+    }
+
+    #[test]
+    fn skips_comments() {
+        let entries = parse(
+            r#"text outside of entries is a comment, even with an email@address.com
+            @comment{an {entry} that's commented out}
+            @comment(another one, with {a ) in braces})
+            @misc{key, title = {Title}}"#,
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(field(&entries, "key", "title"), "Title");
+    }
+
+    #[test]
+    fn reports_malformed_input() {
+        let unterminated = parse_error("@misc{key,\n  title = {Title\n");
+        assert!(
+            unterminated.contains("missing a closing `}`"),
+            "{}",
+            unterminated
+        );
+        let unknown = parse_error("@misc{key, publisher = acm}");
+        assert!(unknown.contains("unknown @string `acm`"), "{}", unknown);
+        let keyless = parse_error("@misc{, title = {Title}}");
+        assert!(keyless.contains("without a key"), "{}", keyless);
+        let twice = parse_error("@misc{key}\n@misc{key}");
+        assert_eq!(twice, "line 2: entry `key` is defined twice");
+        let separator = parse_error("@misc{key title = {Title}}");
+        assert!(separator.contains("expected `,` or `}`"), "{}", separator);
+    }
+}
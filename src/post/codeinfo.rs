@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+/// The info string of a fenced code block, i.e. everything after the opening fence:
+/// ```` ```lang key="value" key=value flag ````
+#[derive(Debug, Default)]
+pub struct CodeInfo {
+    pub lang: String,
+    /// attributes following the language, flags are stored with an empty value
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// split `key="value with spaces" key=value flag` into tokens, keeping quoted values together
+pub fn split_tokens(src: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut tokens: Vec<String> = Vec::default();
+    let mut token = String::default();
+    let mut in_quotes = false;
+    for c in src.trim().chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(Box::from(format!("unterminated quote in `{}`", src)));
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// parse `key=value` and `flag` tokens into attributes, flags are stored with an empty value
+pub fn parse_attributes<I: IntoIterator<Item = String>>(tokens: I) -> BTreeMap<String, String> {
+    let mut attributes: BTreeMap<String, String> = BTreeMap::default();
+    for token in tokens {
+        match token.find('=') {
+            Some(i) => attributes.insert(token[..i].to_owned(), token[i + 1..].to_owned()),
+            None => attributes.insert(token, String::default()),
+        };
+    }
+    attributes
+}
+
+impl CodeInfo {
+    pub fn parse(info: &str) -> Result<CodeInfo, Box<dyn std::error::Error>> {
+        let mut tokens = split_tokens(info)?.into_iter().peekable();
+        let lang = match tokens.peek() {
+            Some(t) if !t.contains('=') => tokens.next().expect("peeked token"),
+            _ => String::default(),
+        };
+        let attributes = parse_attributes(tokens);
+
+        Ok(CodeInfo { lang, attributes })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.attributes.get(key).map(String::as_str)
+    }
+
+    pub fn has(&self, key: &str) -> bool {
+        self.attributes.contains_key(key)
+    }
+
+    /// parse an attribute listing line numbers and ranges of line numbers, i.e. `3-5 8` or
+    /// `3-5,8`, into the list of line numbers
+    pub fn line_ranges(&self, key: &str) -> Result<Option<Vec<usize>>, Box<dyn std::error::Error>> {
+        let ranges = match self.get(key) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+
+        let invalid = || -> Box<dyn std::error::Error> {
+            Box::from(format!(
+                "invalid line range `{}=\"{}\"`, expected something like `3-5 8`",
+                key, ranges
+            ))
+        };
+
+        let mut lines: Vec<usize> = Vec::default();
+        for range in ranges
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|r| !r.is_empty())
+        {
+            match range.find('-') {
+                Some(i) => {
+                    let start: usize = range[..i].trim().parse().map_err(|_| invalid())?;
+                    let end: usize = range[i + 1..].trim().parse().map_err(|_| invalid())?;
+                    if start == 0 || end < start {
+                        return Err(invalid());
+                    }
+                    lines.extend(start..=end);
+                }
+                None => {
+                    let line: usize = range.trim().parse().map_err(|_| invalid())?;
+                    if line == 0 {
+                        return Err(invalid());
+                    }
+                    lines.push(line);
+                }
+            }
+        }
+        Ok(Some(lines))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_language_and_attributes() {
+        let info = CodeInfo::parse(r#"rust title="src/main.rs" linenos=5 nohighlight"#).unwrap();
+        assert_eq!(info.lang, "rust");
+        assert_eq!(info.get("title"), Some("src/main.rs"));
+        assert_eq!(info.get("linenos"), Some("5"));
+        assert!(info.has("nohighlight"));
+        assert_eq!(info.get("nohighlight"), Some(""));
+    }
+
+    #[test]
+    fn info_strings_without_a_language() {
+        let info = CodeInfo::parse(r#"title="notes.txt""#).unwrap();
+        assert_eq!(info.lang, "");
+        assert_eq!(info.get("title"), Some("notes.txt"));
+
+        let info = CodeInfo::parse("").unwrap();
+        assert_eq!(info.lang, "");
+        assert!(info.attributes.is_empty());
+    }
+
+    #[test]
+    fn quoted_values_keep_their_spaces() {
+        let tokens = split_tokens(r#"  caption="a  caption with spaces"   flag "#).unwrap();
+        assert_eq!(tokens, vec!["caption=a  caption with spaces", "flag"]);
+    }
+
+    #[test]
+    fn unterminated_quotes_are_errors() {
+        assert!(split_tokens(r#"title="main.rs"#).is_err());
+        assert!(CodeInfo::parse(r#"rust title="main.rs"#).is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_kept_for_whatever_reads_them() {
+        let info = CodeInfo::parse("python frobnicate=yes").unwrap();
+        assert_eq!(info.get("frobnicate"), Some("yes"));
+        assert_eq!(info.get("title"), None);
+        assert!(!info.has("title"));
+    }
+
+    #[test]
+    fn later_attributes_replace_earlier_ones() {
+        let attributes = parse_attributes(vec!["a=1".to_owned(), "a=2".to_owned()]);
+        assert_eq!(attributes.get("a").map(String::as_str), Some("2"));
+    }
+
+    #[test]
+    fn line_ranges() {
+        let info = CodeInfo::parse(r#"rust hl_lines="1-3,5" other="2 4-4  7""#).unwrap();
+        assert_eq!(
+            info.line_ranges("hl_lines").unwrap(),
+            Some(vec![1, 2, 3, 5])
+        );
+        assert_eq!(info.line_ranges("other").unwrap(), Some(vec![2, 4, 7]));
+        assert_eq!(info.line_ranges("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn empty_line_ranges_have_no_lines() {
+        let info = CodeInfo::parse(r#"rust hl_lines="""#).unwrap();
+        assert_eq!(info.line_ranges("hl_lines").unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn invalid_line_ranges_are_errors() {
+        for ranges in &["5-3", "0", "0-2", "3-", "-3", "a", "1-b", "1.5"] {
+            let info = CodeInfo::parse(&format!(r#"rust hl_lines="{}""#, ranges)).unwrap();
+            assert!(
+                info.line_ranges("hl_lines").is_err(),
+                "`{}` should be invalid",
+                ranges
+            );
+        }
+    }
+}
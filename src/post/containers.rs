@@ -0,0 +1,250 @@
+use super::admonitions::{admonition_open, is_admonition, ADMONITION_CLOSE};
+use super::codeinfo::CodeInfo;
+use super::markdown::{escape_html, fenced_ranges, FormatOptions};
+use comrak::nodes::{Ast, AstNode, NodeValue};
+use comrak::Arena;
+use std::cell::RefCell;
+
+lazy_static::lazy_static! {
+    static ref CONTAINER_REGEX: regex::Regex = regex::Regex::new(r#"^ {0,3}:{3,}[ \t]*(?:([A-Za-z][\w-]*)[ \t]*(.*?))?[ \t]*$"#).expect("valid regex");
+    static ref MARKER_REGEX: regex::Regex = regex::Regex::new(r#"^<!-- container(?::([\w-]+)(?: (.*))?)? -->\s*$"#).expect("valid regex");
+}
+
+/// Swap the lines opening (`:::name args`) and closing (`:::`) containers for html comments.
+/// Comments are html blocks of their own that can interrupt a paragraph without needing blank
+/// lines around them, so the container's contents are still parsed as markdown, and as each
+/// line is swapped for a single line the line numbers of everything else are untouched.
+pub fn mark_containers(src: &str) -> String {
+    let fences = fenced_ranges(src);
+    let mut output = String::with_capacity(src.len());
+    let mut pos = 0;
+    for line in src.split_inclusive('\n') {
+        let start = pos;
+        pos += line.len();
+        let content = line.trim_end_matches(|c| c == '\n' || c == '\r');
+        let caps = match CONTAINER_REGEX.captures(content) {
+            Some(caps) if !fences.iter().any(|f| f.contains(&start)) => caps,
+            _ => {
+                output.push_str(line);
+                continue;
+            }
+        };
+        match caps.get(1) {
+            Some(name) => {
+                let args = caps.get(2).map(|a| a.as_str()).unwrap_or_default();
+                output.push_str(&format!("<!-- container:{}", name.as_str()));
+                if !args.is_empty() {
+                    output.push(' ');
+                    output.push_str(&args.replace("--", "&#45;&#45;"));
+                }
+                output.push_str(" -->");
+            }
+            None => output.push_str("<!-- container -->"),
+        }
+        output.push_str(&line[content.len()..]);
+    }
+    output
+}
+
+struct Marker<'a> {
+    node: &'a AstNode<'a>,
+    name: Option<String>,
+    args: String,
+}
+
+fn find_markers<'a>(root: &'a AstNode<'a>) -> Vec<Marker<'a>> {
+    root.descendants()
+        .filter_map(|node| {
+            let literal = match &node.data.borrow().value {
+                NodeValue::HtmlBlock(block) => String::from_utf8_lossy(&block.literal).into_owned(),
+                _ => return None,
+            };
+            let caps = MARKER_REGEX.captures(&literal)?;
+            Some(Marker {
+                node,
+                name: caps.get(1).map(|n| n.as_str().to_owned()),
+                args: caps
+                    .get(2)
+                    .map(|a| a.as_str().replace("&#45;", "-"))
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+fn set_html<'a>(node: &'a AstNode<'a>, html: &str) {
+    node.data.borrow_mut().value = NodeValue::HtmlInline(html.as_bytes().to_vec());
+}
+
+fn html_node<'a>(arena: &'a Arena<AstNode<'a>>, html: String) -> &'a AstNode<'a> {
+    arena.alloc(comrak::arena_tree::Node::new(RefCell::new(Ast::new(
+        NodeValue::HtmlInline(html.into_bytes()),
+    ))))
+}
+
+/// the nodes between `open` and `close`
+fn contents<'a>(open: &'a AstNode<'a>, close: &'a AstNode<'a>) -> Vec<&'a AstNode<'a>> {
+    let mut nodes: Vec<&'a AstNode<'a>> = Vec::default();
+    let mut node = open.next_sibling();
+    while let Some(n) = node {
+        if std::ptr::eq(n, close) {
+            break;
+        }
+        nodes.push(n);
+        node = n.next_sibling();
+    }
+    nodes
+}
+
+/// label a code block's tab with its `label` or `title`, falling back to its language
+fn tab_label(info: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let info = CodeInfo::parse(info)?;
+    let label = info
+        .get("label")
+        .or_else(|| info.get("title"))
+        .unwrap_or(info.lang.as_str());
+    if label.is_empty() {
+        return Err(Box::from(
+            "code blocks in `:::tabs` need a language, `label` or `title` to name their tab",
+        ));
+    }
+    Ok(label.to_owned())
+}
+
+/// Wrap each of the code blocks in a `:::tabs` group in a panel labelled with its language. The
+/// panels are stacked one after the other until `tabs.js` turns them into tabs.
+fn render_tabs<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    open: &'a AstNode<'a>,
+    close: &'a AstNode<'a>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut panels: Vec<(&'a AstNode<'a>, String)> = Vec::default();
+    for node in contents(open, close) {
+        match &node.data.borrow().value {
+            NodeValue::CodeBlock(block) => {
+                panels.push((node, tab_label(&String::from_utf8_lossy(&block.info))?))
+            }
+            _ => return Err(Box::from("`:::tabs` can only contain fenced code blocks")),
+        }
+    }
+    if panels.is_empty() {
+        return Err(Box::from("`:::tabs` needs at least one fenced code block"));
+    }
+
+    for (node, label) in panels {
+        let label = escape_html(&label);
+        node.insert_before(html_node(
+            arena,
+            format!(
+                r#"<div class="tab-panel" data-label="{}"><p class="tab-label">{}</p>"#,
+                label, label
+            ),
+        ));
+        node.insert_after(html_node(arena, "</div>".to_owned()));
+    }
+    set_html(open, r#"<div class="tabs">"#);
+    set_html(close, "</div>");
+    Ok(())
+}
+
+/// render a container whose contents are the siblings between `open` and `close`, returning
+/// whether it was a group of tabs
+fn render_container<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    name: &str,
+    args: &str,
+    open: &'a AstNode<'a>,
+    close: &'a AstNode<'a>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if is_admonition(name) {
+        set_html(open, &admonition_open(name, args));
+        set_html(close, ADMONITION_CLOSE);
+        return Ok(false);
+    }
+    match name {
+        "tabs" => {
+            render_tabs(arena, open, close)?;
+            Ok(true)
+        }
+        "details" => {
+            let summary = match args.trim() {
+                "" => "Details".to_owned(),
+                summary => escape_html(summary),
+            };
+            set_html(open, &format!("<details><summary>{}</summary>", summary));
+            set_html(close, "</details>");
+            Ok(false)
+        }
+        _ => Err(Box::from(format!("unknown container `:::{}`", name))),
+    }
+}
+
+/// Render the containers marked by [`mark_containers`], returning whether there were any groups
+/// of tabs. A container must be closed within the block it was opened in, so that its contents
+/// are siblings in the document.
+pub fn render_containers<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    root: &'a AstNode<'a>,
+    options: &FormatOptions,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut has_tabs = false;
+    let mut open: Vec<Marker<'a>> = Vec::default();
+    for marker in find_markers(root) {
+        let line = marker.node.data.borrow().start_line as usize;
+        if marker.name.is_some() {
+            open.push(marker);
+            continue;
+        }
+
+        let opening = match open.pop() {
+            Some(o) => o,
+            None => {
+                let error = Box::from("`:::` without an opening `:::name`");
+                set_html(
+                    marker.node,
+                    &options.render_error(line, "container", false, error)?,
+                );
+                continue;
+            }
+        };
+        let name = opening.name.as_deref().unwrap_or_default();
+        let what = format!("`:::{}` container", name);
+        let opening_line = opening.node.data.borrow().start_line as usize;
+        let same_block = match (opening.node.parent(), marker.node.parent()) {
+            (Some(a), Some(b)) => std::ptr::eq(a, b),
+            _ => false,
+        };
+        let rendered = if same_block {
+            render_container(arena, name, &opening.args, opening.node, marker.node)
+        } else {
+            Err(Box::from(format!(
+                "the container is closed on line {} outside of the block it was opened in",
+                line + options.line_offset
+            )))
+        };
+        match rendered {
+            Ok(tabs) => has_tabs |= tabs,
+            Err(e) => {
+                set_html(
+                    opening.node,
+                    &options.render_error(opening_line, &what, false, e)?,
+                );
+                set_html(marker.node, "");
+            }
+        }
+    }
+
+    for marker in open {
+        let line = marker.node.data.borrow().start_line as usize;
+        let what = format!(
+            "`:::{}` container",
+            marker.name.as_deref().unwrap_or_default()
+        );
+        let error = Box::from("the container is never closed with `:::`");
+        set_html(
+            marker.node,
+            &options.render_error(line, &what, false, error)?,
+        );
+    }
+    Ok(has_tabs)
+}
@@ -0,0 +1,12 @@
+use super::svg::strip_prolog;
+use super::tool::run_tool;
+use crate::cache::cached;
+
+pub fn create_graphviz_svg(src: &str) -> Result<String, Box<dyn std::error::Error>> {
+    use std::process::Command;
+
+    let svg = cached("graphviz", src, || {
+        run_tool(Command::new("dot").arg("-Tsvg"), src)
+    })?;
+    strip_prolog(&svg)
+}
@@ -0,0 +1,157 @@
+use super::codeinfo::CodeInfo;
+use std::path::Path;
+
+/// the name of the region that an `ANCHOR: name` or `ANCHOR_END: name` comment on `line` marks,
+/// ignoring whatever closes the comment (i.e. `*/` or `-->`)
+fn anchor_name<'l>(line: &'l str, marker: &str) -> Option<&'l str> {
+    let rest = &line[line.find(marker)? + marker.len()..];
+    let name = rest.split_whitespace().next()?;
+    Some(name.trim_end_matches(|c: char| !(c.is_alphanumeric() || c == '_')))
+}
+
+/// Load the source for a code block with an `include="path"` attribute, relative to the post.
+/// `lines="10-42"` picks out a range of lines, while `region="name"` picks out the lines between
+/// `ANCHOR: name` and `ANCHOR_END: name` comments. The included file is read on every build, so
+/// anything cached on the block's source is invalidated when the file changes.
+pub fn resolve_include(
+    info: &CodeInfo,
+    post: &Path,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let include = match info.get("include") {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    let path = post.parent().unwrap_or_else(|| Path::new("")).join(include);
+    if info.has("lines") && info.has("region") {
+        return Err(Box::from(
+            "an include can pick out either `lines` or a `region`, not both",
+        ));
+    }
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| format!("failed to include `{}`: {}", path.display(), e))?;
+
+    if let Some(lines) = info.line_ranges("lines")? {
+        let all: Vec<&str> = contents.lines().collect();
+        let mut selected = String::default();
+        for line in lines {
+            match all.get(line - 1) {
+                Some(l) => {
+                    selected.push_str(l);
+                    selected.push('\n');
+                }
+                None => {
+                    return Err(Box::from(format!(
+                        "can't include line {} of `{}` as it only has {} lines",
+                        line,
+                        path.display(),
+                        all.len()
+                    )))
+                }
+            }
+        }
+        return Ok(Some(selected));
+    }
+
+    if let Some(region) = info.get("region") {
+        let start = format!("ANCHOR: {}", region);
+        let end = format!("ANCHOR_END: {}", region);
+        let mut selected = String::default();
+        let mut found = false;
+        let mut inside = false;
+        for line in contents.lines() {
+            let opens = anchor_name(line, "ANCHOR: ");
+            let closes = anchor_name(line, "ANCHOR_END: ");
+            if opens == Some(region) {
+                found = true;
+                inside = true;
+            } else if closes == Some(region) {
+                inside = false;
+            } else if inside && opens.is_none() && closes.is_none() {
+                selected.push_str(line);
+                selected.push('\n');
+            }
+        }
+        if !found {
+            return Err(Box::from(format!(
+                "can't find region `{}` (marked with `{}`) in `{}`",
+                region,
+                start,
+                path.display()
+            )));
+        }
+        if inside {
+            return Err(Box::from(format!(
+                "region `{}` in `{}` is never closed with `{}`",
+                region,
+                path.display(),
+                end
+            )));
+        }
+        return Ok(Some(selected));
+    }
+
+    Ok(Some(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::tool::TempDir;
+
+    const SOURCE: &str = "fn main() {
+    /* ANCHOR: setup */
+    let x = 1;
+    // ANCHOR_END: setup
+    // ANCHOR: setup_more
+    let y = 2;
+    // ANCHOR_END: setup_more
+}
+";
+
+    fn include(test: &str, info: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let temp = TempDir::new(&format!("blogham-include-{}-{}", test, std::process::id()))?;
+        std::fs::write(temp.path().join("main.rs"), SOURCE)?;
+        let info = CodeInfo::parse(&format!(r#"rust include="main.rs" {}"#, info))?;
+        resolve_include(&info, &temp.path().join("post.md"))
+    }
+
+    #[test]
+    fn includes_whole_files() {
+        assert_eq!(include("whole", "").unwrap().as_deref(), Some(SOURCE));
+        let info = CodeInfo::parse("rust").unwrap();
+        assert!(resolve_include(&info, Path::new("post.md"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn includes_line_ranges() {
+        assert_eq!(
+            include("ranges", r#"lines="1 3 6-8""#).unwrap().as_deref(),
+            Some("fn main() {\n    let x = 1;\n    let y = 2;\n    // ANCHOR_END: setup_more\n}\n")
+        );
+        // ranges have to say where they end
+        assert!(include("open", r#"lines="6-""#).is_err());
+        assert!(include("out-of-range", r#"lines="8-9""#).is_err());
+    }
+
+    #[test]
+    fn includes_regions() {
+        assert_eq!(
+            include("region", r#"region="setup""#).unwrap().as_deref(),
+            Some("    let x = 1;\n")
+        );
+        assert_eq!(
+            include("region-more", r#"region="setup_more""#)
+                .unwrap()
+                .as_deref(),
+            Some("    let y = 2;\n")
+        );
+        assert!(include("missing", r#"region="teardown""#).is_err());
+    }
+
+    #[test]
+    fn lines_and_regions_are_exclusive() {
+        assert!(include("both", r#"lines="1-2" region="setup""#).is_err());
+    }
+}
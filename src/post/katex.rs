@@ -1,78 +1,252 @@
-pub fn create_katex_block(src: &str) -> Result<String, Box<dyn std::error::Error>> {
-    use std::io::Write;
-    use std::process::{Command, Stdio};
-
-    let mut child = match Command::new("katex")
-        .arg("-d")
-        .arg("-t")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-    {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("failed to launch katex, not rendering math block: {:?}", e);
-            return Err(Box::from(e));
+use super::codeinfo::CodeInfo;
+use super::tool::run_tool;
+use comrak::nodes::{AstNode, NodeValue};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+lazy_static::lazy_static! {
+    static ref LABEL_REGEX: regex::Regex = regex::Regex::new(r#"\\label\{([^}]+)\}"#).expect("valid regex");
+    static ref REF_REGEX: regex::Regex = regex::Regex::new(r#"\\(eq)?ref\{([^}]+)\}"#).expect("valid regex");
+    /// blocks that opt out of numbering, or number themselves
+    static ref UNNUMBERED_REGEX: regex::Regex = regex::Regex::new(r#"\\(?:notag|nonumber|tag)\b|\\begin\{(?:equation|align|alignat|gather|multline)\}"#).expect("valid regex");
+}
+
+/// Equation numbers for a post's katex code blocks, in the order they appear in the post. Every block
+/// is numbered unless it is unlabelled and uses `\notag`, `\nonumber`, its own `\tag` or an
+/// environment that numbers its own lines (`align`, `gather`, ...)
+#[derive(Default)]
+pub struct Equations {
+    numbers: HashMap<String, usize>,
+    /// the numbers of the numbered blocks by their source, taken as each block is rendered
+    blocks: RefCell<HashMap<String, VecDeque<usize>>>,
+}
+
+impl Equations {
+    pub fn scan<'a>(root: &'a AstNode<'a>) -> Result<Equations, Box<dyn std::error::Error>> {
+        let mut numbers: HashMap<String, usize> = HashMap::default();
+        let mut blocks: HashMap<String, VecDeque<usize>> = HashMap::default();
+        let mut count = 0;
+        for node in root.descendants() {
+            let literal = match &node.data.borrow().value {
+                NodeValue::CodeBlock(block) => {
+                    match CodeInfo::parse(&String::from_utf8_lossy(&block.info)) {
+                        Ok(info) if info.lang == "katex" => {
+                            String::from_utf8_lossy(&block.literal).into_owned()
+                        }
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let block = literal.as_str();
+            let labels: Vec<&str> = LABEL_REGEX
+                .captures_iter(block)
+                .map(|label| label.get(1).expect("1 capture group").as_str().trim())
+                .collect();
+            if labels.is_empty() && UNNUMBERED_REGEX.is_match(block) {
+                continue;
+            }
+            count += 1;
+            blocks
+                .entry(block.trim().to_owned())
+                .or_default()
+                .push_back(count);
+            for label in labels {
+                if numbers.insert(label.to_owned(), count).is_some() {
+                    return Err(Box::from(format!(
+                        "equation label `{}` is defined more than once",
+                        label
+                    )));
+                }
+            }
         }
-    };
+        Ok(Equations {
+            numbers,
+            blocks: RefCell::new(blocks),
+        })
+    }
+
+    /// the number of the next block with this source, if it is numbered
+    fn block_number(&self, src: &str) -> Option<usize> {
+        self.blocks
+            .borrow_mut()
+            .get_mut(src.trim())
+            .and_then(VecDeque::pop_front)
+    }
+
+    pub fn anchor(label: &str) -> String {
+        format!("eq-{}", label.trim())
+    }
+
+    pub fn number(&self, label: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        match self.numbers.get(label.trim()) {
+            Some(n) => Ok(*n),
+            None => Err(Box::from(format!(
+                "reference to unknown equation label `{}`",
+                label.trim()
+            ))),
+        }
+    }
+
+    /// rewrite `\ref{...}` and `\eqref{...}` inside of math into KaTeX links
+    fn resolve_refs(&self, src: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let mut error: Option<Box<dyn std::error::Error>> = None;
+        let resolved = REF_REGEX.replace_all(src, |caps: &regex::Captures| {
+            let label = caps.get(2).expect("2 capture groups").as_str();
+            match self.number(label) {
+                Ok(number) if caps.get(1).is_some() => {
+                    format!(r"\href{{#{}}}{{({})}}", Equations::anchor(label), number)
+                }
+                Ok(number) => format!(r"\href{{#{}}}{{{}}}", Equations::anchor(label), number),
+                Err(e) => {
+                    error = Some(e);
+                    caps.get(0).expect("match").as_str().to_owned()
+                }
+            }
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(resolved.into_owned()),
+        }
+    }
+}
+
+fn render_katex(
+    src: &str,
+    display: bool,
+    macros: &BTreeMap<String, String>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    use std::process::Command;
 
-    let stdin = child.stdin.as_mut().expect("valid katex stdin");
-    stdin.write_all(src.as_ref())?;
-
-    let output = child.wait_with_output()?;
-    if !output.status.success() {
-        eprintln!(
-            "failed to generate katex, exit code: {:?}",
-            output.status.code()
-        );
-        eprintln!("katex STDOUT:");
-        eprintln!("{}", String::from_utf8_lossy(output.stdout.as_ref()));
-        eprintln!("katex STDERR:");
-        eprintln!("{}", String::from_utf8_lossy(output.stdout.as_ref()));
-        eprintln!("/katex output");
-        return Err(Box::from("katex failed"));
+    let mut command = Command::new("katex");
+    if display {
+        command.arg("-d");
+    }
+    command.arg("--trust");
+    for (name, expansion) in macros.iter() {
+        let name = if name.starts_with('\\') {
+            name.to_owned()
+        } else {
+            format!("\\{}", name)
+        };
+        command
+            .arg("--macro")
+            .arg(format!("{}:{}", name, expansion));
     }
-    let rendered: String = String::from_utf8(output.stdout)?;
 
-    Ok(format!(r#"<figure class="math">{}</figure>"#, rendered))
+    run_tool(&mut command, src)
 }
 
-pub fn create_katex_inline(src: &str) -> Result<String, Box<dyn std::error::Error>> {
-    use std::io::Write;
-    use std::process::{Command, Stdio};
-
-    let mut child = match Command::new("katex")
-        .arg("-t")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-    {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("failed to launch katex, not rendering math block: {:?}", e);
-            return Err(Box::from(e));
+pub fn create_katex_block(
+    src: &str,
+    macros: &BTreeMap<String, String>,
+    equations: &Equations,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // katex only takes a single `\tag` per display block
+    if LABEL_REGEX.find_iter(src).count() > 1 {
+        return Err(Box::from(
+            "a katex block can only have one `\\label`, split the equations into separate blocks",
+        ));
+    }
+
+    // swap the label for its equation number, remembering the anchor to emit
+    let number = equations.block_number(src);
+    let mut anchor: Option<String> = None;
+    let mut error: Option<Box<dyn std::error::Error>> = None;
+    let src = LABEL_REGEX.replace_all(src, |caps: &regex::Captures| {
+        let label = caps.get(1).expect("1 capture group").as_str();
+        match equations.number(label) {
+            Ok(number) => {
+                anchor = Some(Equations::anchor(label));
+                format!(r"\tag{{{}}}", number)
+            }
+            Err(e) => {
+                error = Some(e);
+                String::default()
+            }
+        }
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+    // unlabelled blocks are numbered too, with an anchor made from their number
+    let src = match number {
+        Some(number) if anchor.is_none() => {
+            anchor = Some(Equations::anchor(&number.to_string()));
+            format!("{}\n\\tag{{{}}}", src.trim_end(), number)
         }
+        _ => src.into_owned(),
     };
+    let src = equations.resolve_refs(&src)?;
 
-    let stdin = child.stdin.as_mut().expect("valid katex stdin");
-    stdin.write_all(src.as_ref())?;
-
-    let output = child.wait_with_output()?;
-    if !output.status.success() {
-        eprintln!(
-            "failed to generate katex, exit code: {:?}",
-            output.status.code()
-        );
-        eprintln!("katex STDOUT:");
-        eprintln!("{}", String::from_utf8_lossy(output.stdout.as_ref()));
-        eprintln!("katex STDERR:");
-        eprintln!("{}", String::from_utf8_lossy(output.stdout.as_ref()));
-        eprintln!("/katex output");
-        return Err(Box::from("katex failed"));
+    let rendered = render_katex(&src, true, macros)?;
+
+    match anchor {
+        Some(id) => Ok(format!(
+            r#"<figure class="math" id="{}">{}</figure>"#,
+            id, rendered
+        )),
+        None => Ok(format!(r#"<figure class="math">{}</figure>"#, rendered)),
     }
-    let rendered: String = String::from_utf8(output.stdout)?;
+}
+
+pub fn create_katex_inline(
+    src: &str,
+    macros: &BTreeMap<String, String>,
+    equations: &Equations,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let src = equations.resolve_refs(src)?;
+    render_katex(&src, false, macros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comrak::{parse_document, Arena, ComrakOptions};
 
-    Ok(rendered)
+    fn scan(src: &str) -> Result<Equations, Box<dyn std::error::Error>> {
+        let arena = Arena::new();
+        Equations::scan(parse_document(&arena, src, &ComrakOptions::default()))
+    }
+
+    #[test]
+    fn numbers_every_display_block() {
+        let src = "```katex\na = b \\label{eq:first}\n```\n\n```katex\nc = d\n```\n\n\
+                   ```katex\n\\begin{align} e &= f \\end{align}\n```\n\n\
+                   ```katex\ng = h \\notag\n```\n\n```katex\nc = d\n```\n";
+        let equations = scan(src).unwrap();
+        assert_eq!(equations.number("eq:first").unwrap(), 1);
+        assert_eq!(equations.block_number("a = b \\label{eq:first}"), Some(1));
+        // identical blocks are numbered in the order they're rendered
+        assert_eq!(equations.block_number("c = d\n"), Some(2));
+        assert_eq!(equations.block_number("c = d\n"), Some(3));
+        assert_eq!(equations.block_number("c = d\n"), None);
+        assert_eq!(equations.block_number("g = h \\notag"), None);
+    }
+
+    #[test]
+    fn labels_can_only_be_defined_once() {
+        let src = "```katex\na \\label{x}\n```\n\n```katex\nb \\label{x}\n```\n";
+        assert!(scan(src).is_err());
+    }
+
+    #[test]
+    fn numbers_nested_and_tilde_fenced_blocks() {
+        let src = "~~~katex\na \\label{eq:tilde}\n~~~\n\n\
+                   - item\n\n  ```katex\n  b \\label{eq:list}\n  ```\n\n\
+                   > [!NOTE]\n> ```katex\n> c \\label{eq:note}\n> ```\n";
+        let equations = scan(src).unwrap();
+        assert_eq!(equations.number("eq:tilde").unwrap(), 1);
+        assert_eq!(equations.number("eq:list").unwrap(), 2);
+        assert_eq!(equations.number("eq:note").unwrap(), 3);
+    }
+
+    #[test]
+    fn skips_katex_examples_in_other_fences() {
+        let src = "````markdown\n```katex\na \\label{eq:example}\n```\n````\n\n\
+                   ```katex\nb \\label{eq:real}\n```\n";
+        let equations = scan(src).unwrap();
+        assert!(equations.number("eq:example").is_err());
+        assert_eq!(equations.number("eq:real").unwrap(), 1);
+    }
 }
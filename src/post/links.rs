@@ -0,0 +1,135 @@
+use super::markdown::{escape_html, source_line, FormatOptions};
+use comrak::nodes::{AstNode, NodeValue};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+lazy_static::lazy_static! {
+    /// `[[slug]]`, `[[Post Title]]` or `[[slug#fragment|link text]]`
+    pub static ref WIKI_LINK_REGEX: regex::Regex = regex::Regex::new(r#"\[\[([^\[\]|#]+)(#[^\[\]|]*)?(?:\|([^\[\]]+))?\]\]"#).expect("valid regex");
+    static ref POST_HREF_REGEX: regex::Regex = regex::Regex::new(r##"href="(?:https?://blog\.hamaluik\.ca)?(/posts/[^/"#?]+/)"##).expect("valid regex");
+}
+
+/// what a post needs to know about another post to link to it
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkTarget {
+    pub url: String,
+    pub title: String,
+    pub slug: String,
+}
+
+/// split `post.md#fragment` into the path and the fragment (including its `#`)
+fn split_fragment(url: &str) -> (&str, &str) {
+    match url.find('#') {
+        Some(i) => (&url[..i], &url[i..]),
+        None => (url, ""),
+    }
+}
+
+fn is_relative_markdown(path: &str) -> bool {
+    path.ends_with(".md") && !path.starts_with('/') && !path.contains(':')
+}
+
+/// Rewrite links to other posts' markdown sources (`[part 1](2017-04-23-part-1.md#intro)`) into
+/// links to where those posts are published, so that links between posts survive slug changes.
+/// Links to posts that don't exist or aren't published are errors, which (unless the build is
+/// strict) leave the link's text followed by the error.
+pub fn resolve_post_links<'a>(
+    root: &'a AstNode<'a>,
+    options: &FormatOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let links: Vec<&'a AstNode<'a>> = root
+        .descendants()
+        .filter(|node| matches!(node.data.borrow().value, NodeValue::Link(_)))
+        .collect();
+    for node in links {
+        let url = match &node.data.borrow().value {
+            NodeValue::Link(link) => String::from_utf8_lossy(&link.url).into_owned(),
+            _ => continue,
+        };
+        let (path, fragment) = split_fragment(&url);
+        if !is_relative_markdown(path) {
+            continue;
+        }
+
+        let resolved = options
+            .source
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(path);
+        let name = resolved
+            .file_name()
+            .and_then(std::ffi::OsStr::to_str)
+            .unwrap_or_default();
+        match options.posts.get(name) {
+            Some(target) => {
+                if let NodeValue::Link(ref mut link) = node.data.borrow_mut().value {
+                    link.url = format!("{}{}", target.url, fragment).into_bytes();
+                }
+            }
+            None => {
+                let error = if resolved.exists() {
+                    format!("`{}` isn't published", resolved.display())
+                } else {
+                    format!("`{}` doesn't exist", resolved.display())
+                };
+                let error = options.render_error(
+                    source_line(node),
+                    &format!("link to `{}`", path),
+                    true,
+                    Box::from(error),
+                )?;
+                // swap the link for its text so that nothing points at the markdown
+                let children: Vec<&'a AstNode<'a>> = node.children().collect();
+                for child in children {
+                    child.detach();
+                    node.insert_before(child);
+                }
+                node.data.borrow_mut().value = NodeValue::HtmlInline(error.into_bytes());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Turn a `[[slug]]` or `[[Post Title]]` match from the prose into a link to that post, the
+/// link's text being the post's title unless it is given with `[[slug|text]]`
+pub fn wiki_link_html(
+    caps: &regex::Captures,
+    posts: &BTreeMap<String, LinkTarget>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let name = caps.get(1).expect("3 capture groups").as_str().trim();
+    let fragment = caps.get(2).map(|f| f.as_str()).unwrap_or_default();
+    let target = posts
+        .values()
+        .find(|post| post.slug == name)
+        .or_else(|| {
+            posts
+                .values()
+                .find(|post| post.title.eq_ignore_ascii_case(name))
+        })
+        .ok_or_else(|| {
+            format!(
+                "there is no published post with the slug or title `{}`",
+                name
+            )
+        })?;
+    let text = match caps.get(3) {
+        Some(text) => text.as_str().trim(),
+        None => target.title.as_str(),
+    };
+    Ok(format!(
+        r#"<a class="wikilink" href="{}{}">{}</a>"#,
+        escape_html(&target.url),
+        escape_html(fragment),
+        escape_html(text)
+    ))
+}
+
+/// the urls of the posts linked to from a post's rendered html
+pub fn linked_posts(html: &str) -> BTreeSet<String> {
+    POST_HREF_REGEX
+        .captures_iter(html)
+        .map(|caps| caps[1].to_owned())
+        .collect()
+}
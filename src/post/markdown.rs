@@ -1,7 +1,33 @@
-use super::katex::{create_katex_block, create_katex_inline};
+use super::admonitions::convert_alert_blockquotes;
+use super::attributes::{
+    apply_attribute_lists, collect_attribute_lists, inject_heading_attributes, Attributes,
+};
+use super::bibliography::{Bibliography, CITATION_REGEX};
+use super::codeinfo::CodeInfo;
+use super::containers::{mark_containers, render_containers};
+use super::graphviz::create_graphviz_svg;
+use super::include::resolve_include;
+use super::katex::{create_katex_block, create_katex_inline, Equations};
+use super::links::{resolve_post_links, wiki_link_html, LinkTarget, WIKI_LINK_REGEX};
+use super::mermaid::create_mermaid_svg;
+use super::numbering::{number_tables, Numbering, CROSSREF_REGEX};
 use super::plantuml::create_plantuml_svg;
 use super::pygments::create_code_block;
-use comrak::{ComrakExtensionOptions, ComrakOptions, ComrakParseOptions, ComrakRenderOptions};
+use super::shortcodes::expand_shortcodes;
+use super::sidenotes::convert_footnotes_to_sidenotes;
+use super::snippets::snippet_output;
+use super::svg::{optimise as optimise_svg, prefix_inline_svg_ids};
+use super::tikz::create_tikz_svg;
+use crate::config::{CitationStyle, Images, TestCommand};
+use crate::images::{add_image_attributes, picture_html, placeholder};
+use comrak::nodes::{Ast, AstNode, NodeValue};
+use comrak::{
+    format_html, parse_document, Arena, ComrakExtensionOptions, ComrakOptions, ComrakParseOptions,
+    ComrakRenderOptions,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 
 lazy_static::lazy_static! {
     static ref COMRAK_OPTIONS: ComrakOptions = ComrakOptions {
@@ -36,83 +62,444 @@ lazy_static::lazy_static! {
 pub struct FormatResponse {
     pub output: String,
     pub include_katex_css: bool,
+    /// whether the post has tabbed groups that need `tabs.js` to work
+    pub include_tabs_script: bool,
+    /// whether the post has images with placeholders for `placeholders.js` to fade them in over
+    pub include_placeholder_script: bool,
 }
 
-fn format_code(lang: &str, src: &str) -> Result<FormatResponse, Box<dyn std::error::Error>> {
+#[derive(Default)]
+pub struct FormatOptions {
+    /// the post being formatted, used when reporting errors
+    pub source: PathBuf,
+    /// number of lines preceding the markdown in the source file (i.e. the front matter)
+    pub line_offset: usize,
+    /// fail on render errors rather than rendering them into the page
+    pub strict: bool,
+    /// site-wide macros merged with the post's own `math_macros`
+    pub math_macros: BTreeMap<String, String>,
+    /// how to run code blocks marked `test`, for embedding their output
+    pub tests: BTreeMap<String, TestCommand>,
+    /// render footnotes as sidenotes next to their references
+    pub sidenotes: bool,
+    /// the BibTeX file cited by the post
+    pub bibliography: Option<PathBuf>,
+    pub citation_style: CitationStyle,
+    /// the published posts keyed by the name of their source file, for linking between posts
+    pub posts: BTreeMap<String, LinkTarget>,
+    /// the widths and formats images are served at
+    pub images: Images,
+}
+
+/// A fenced code block pulled out of a post without rendering it
+pub struct CodeBlock {
+    pub info: CodeInfo,
+    pub source: String,
+    /// the line of the opening fence in the post's source file
+    pub line: usize,
+}
+
+impl FormatOptions {
+    /// Deal with part of a post failing to render: in strict mode the whole post fails,
+    /// otherwise the error is reported and rendered into the page where the content should be
+    pub(super) fn render_error(
+        &self,
+        line: usize,
+        what: &str,
+        inline: bool,
+        error: Box<dyn std::error::Error>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let line = line + self.line_offset;
+        let message = format!(
+            "{}:{}: failed to render {}: {}",
+            self.source.display(),
+            line,
+            what,
+            error
+        );
+        if self.strict {
+            return Err(Box::from(message));
+        }
+        eprintln!("{}", message);
+
+        if inline {
+            Ok(format!(
+                r#"<span class="render-error" title="{}">{}</span>"#,
+                escape_html(&error.to_string()),
+                escape_html(what)
+            ))
+        } else {
+            Ok(format!(
+                r#"<div class="render-error"><p>Failed to render {} (line {})</p><pre>{}</pre></div>"#,
+                escape_html(what),
+                line,
+                escape_html(&error.to_string())
+            ))
+        }
+    }
+}
+
+pub fn escape_html(src: &str) -> String {
+    src.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// byte ranges of the fenced code blocks in the markdown, which shouldn't be touched when
+/// preprocessing the markdown's source
+pub fn fenced_ranges(src: &str) -> Vec<std::ops::Range<usize>> {
+    let mut ranges: Vec<std::ops::Range<usize>> = Vec::default();
+    // start of the block, fence character and fence length
+    let mut open: Option<(usize, char, usize)> = None;
+    let mut pos = 0;
+    for line in src.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        let fence_char = trimmed.chars().next().unwrap_or(' ');
+        let fence_length = if fence_char == '`' || fence_char == '~' {
+            trimmed.chars().take_while(|c| *c == fence_char).count()
+        } else {
+            0
+        };
+
+        match open {
+            None if indent <= 3 && fence_length >= 3 => {
+                open = Some((pos, fence_char, fence_length));
+            }
+            Some((start, c, length))
+                if indent <= 3
+                    && fence_char == c
+                    && fence_length >= length
+                    && trimmed[fence_length..].trim().is_empty() =>
+            {
+                ranges.push(start..pos + line.len());
+                open = None;
+            }
+            _ => {}
+        }
+        pos += line.len();
+    }
+    if let Some((start, _, _)) = open {
+        ranges.push(start..src.len());
+    }
+    ranges
+}
+
+/// the line in the markdown a node came from, inline nodes are attributed to their block
+pub(super) fn source_line<'a>(node: &'a AstNode<'a>) -> usize {
+    let mut node = Some(node);
+    while let Some(n) = node {
+        let line = n.data.borrow().start_line as usize;
+        if line > 0 {
+            return line;
+        }
+        node = n.parent();
+    }
+    0
+}
+
+/// the `id` attribute for a code block with an `id`
+fn code_id_html(info: &CodeInfo) -> String {
+    match info.get("id") {
+        Some(id) => format!(r#" id="{}""#, escape_html(id)),
+        None => String::default(),
+    }
+}
+
+/// wrap a rendered diagram in a figure (shrinking its svg), with an optional caption from the
+/// code block
+fn wrap_diagram_in_figure(svg: &str, info: &CodeInfo, numbering: &Numbering) -> String {
+    let svg = optimise_svg(svg);
+    let caption = info.get("caption").map(escape_html);
+    match numbering.caption_html(info.get("id"), caption.as_deref()) {
+        Some(caption) => format!(
+            "<figure{}>{}<figcaption>{}</figcaption></figure>",
+            code_id_html(info),
+            svg,
+            caption
+        ),
+        None => format!("<figure{}>{}</figure>", code_id_html(info), svg),
+    }
+}
+
+fn format_code(
+    info: &CodeInfo,
+    src: &str,
+    options: &FormatOptions,
+    numbering: &Numbering,
+) -> Result<FormatResponse, Box<dyn std::error::Error>> {
+    let lang = info.lang.as_str();
+
     // render plantuml code blocks into an inline svg
     if lang == "plantuml" {
         let svg = create_plantuml_svg(src)?;
-        let svg = svg.replace(
-            r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#,
-            "",
-        );
 
         return Ok(FormatResponse {
-            output: format!("<figure>{}</figure>", svg),
+            output: wrap_diagram_in_figure(&svg, info, numbering),
+            include_katex_css: false,
+            include_tabs_script: false,
+            include_placeholder_script: false,
+        });
+    }
+    // render graphviz code blocks into an inline svg
+    if lang == "dot" || lang == "graphviz" {
+        let svg = create_graphviz_svg(src)?;
+        return Ok(FormatResponse {
+            output: wrap_diagram_in_figure(&svg, info, numbering),
+            include_katex_css: false,
+            include_tabs_script: false,
+            include_placeholder_script: false,
+        });
+    }
+    // render mermaid code blocks into inline svgs
+    if lang == "mermaid" {
+        let svg = create_mermaid_svg(src)?;
+        return Ok(FormatResponse {
+            output: wrap_diagram_in_figure(&svg, info, numbering),
+            include_katex_css: false,
+            include_tabs_script: false,
+            include_placeholder_script: false,
+        });
+    }
+    // compile tikz code blocks with latex into an inline svg
+    if lang == "tikz" {
+        let svg = create_tikz_svg(src, info)?;
+        return Ok(FormatResponse {
+            output: wrap_diagram_in_figure(&svg, info, numbering),
             include_katex_css: false,
+            include_tabs_script: false,
+            include_placeholder_script: false,
         });
     }
     // render katex code blocks into an inline math
     if lang == "katex" {
         return Ok(FormatResponse {
-            output: create_katex_block(src)?,
+            output: create_katex_block(src, &options.math_macros, &numbering.equations)?,
             include_katex_css: true,
+            include_tabs_script: false,
+            include_placeholder_script: false,
         });
     }
 
     // otherwise, pass it to pygments
-    let html = create_code_block(src, lang)?;
+    let mut html = create_code_block(src, info)?;
+
+    // show what running the snippet prints under the snippet
+    if info.has("test") && info.has("output") {
+        let output = snippet_output(src, lang, &options.tests)?;
+        html.push_str(&format!(
+            r#"<pre class="output"><samp>{}</samp></pre>"#,
+            escape_html(&output)
+        ));
+    }
+
+    // numbered listings get a caption above them
+    if let Some(label) = info.get("id") {
+        if let Some(name) = numbering.name(label) {
+            let caption = info.get("caption").map(escape_html);
+            html = format!(
+                r#"<figure class="listing"{}><figcaption>{}</figcaption>{}</figure>"#,
+                code_id_html(info),
+                numbering
+                    .caption_html(Some(label), caption.as_deref())
+                    .unwrap_or(name),
+                html
+            );
+        }
+    }
 
     Ok(FormatResponse {
         output: html,
         include_katex_css: false,
+        include_tabs_script: false,
+        include_placeholder_script: false,
     })
 }
 
+/// wrap an image in a figure, its label (or an id from its attribute list) goes on the figure
+/// while everything else goes on the image. A placeholder is shown behind the image until it
+/// loads.
 fn wrap_image_in_figure(
     link: &comrak::nodes::NodeLink,
     alt: &str,
+    label: Option<&str>,
+    attributes: Option<&Attributes>,
+    numbering: &Numbering,
+    images: &Images,
+    placeholder: Option<&str>,
 ) -> Result<String, Box<dyn std::error::Error>> {
     let title = String::from_utf8_lossy(link.title.as_ref());
     let url = String::from_utf8_lossy(link.url.as_ref());
-    if title.len() > 0 {
+    let label = label.or_else(|| attributes.and_then(|a| a.id.as_deref()));
+    let mut figure_attributes = match label {
+        Some(label) => format!(r#" id="{}""#, escape_html(label)),
+        None => String::default(),
+    };
+    let image_attributes = attributes.map(Attributes::rest_html).unwrap_or_default();
+    if let Some(placeholder) = placeholder {
+        figure_attributes.push_str(&format!(
+            r#" class="placeholder" style="--placeholder: url('{}')""#,
+            placeholder
+        ));
+    }
+    let (title_attribute, caption) = if title.len() > 0 {
+        (format!(r#" title="{}""#, title), Some(&*title))
+    } else {
+        (String::default(), None)
+    };
+    let image = picture_html(
+        &url,
+        &format!(r#" alt="{}"{}{}"#, alt, title_attribute, image_attributes),
+        images,
+    );
+    if let Some(caption) = numbering.caption_html(label, caption) {
         Ok(format!(
-            r#"<figure><img src="{}" alt="{}" title="{}"><figcaption>{}</figcaption></figure>"#,
-            url, alt, title, title
+            r#"<figure{}>{}<figcaption>{}</figcaption></figure>"#,
+            figure_attributes, image, caption
         ))
     } else {
         Ok(format!(
-            r#"<figure><img src="{}" alt="{}"></figure>"#,
-            url, alt
+            r#"<figure{}>{}</figure>"#,
+            figure_attributes, image
         ))
     }
 }
 
-pub fn format_markdown(src: &str) -> Result<FormatResponse, Box<dyn std::error::Error>> {
-    use comrak::nodes::{AstNode, NodeValue};
-    use comrak::{format_html, parse_document, Arena};
+/// comrak can leave runs of neighbouring text nodes, i.e. around brackets that didn't turn out to
+/// be links, which are merged so that the passes over the text see all of it at once
+pub(super) fn merge_text_nodes<'a>(root: &'a AstNode<'a>) {
+    let texts: Vec<&'a AstNode<'a>> = root
+        .descendants()
+        .filter(|node| matches!(node.data.borrow().value, NodeValue::Text(_)))
+        .collect();
+    for text in texts {
+        let previous = match text.previous_sibling() {
+            Some(p) => p,
+            None => continue,
+        };
+        if let NodeValue::Text(ref mut before) = previous.data.borrow_mut().value {
+            if let NodeValue::Text(after) = &text.data.borrow().value {
+                before.extend_from_slice(after);
+            }
+        } else {
+            continue;
+        }
+        text.detach();
+    }
+}
+
+/// split a text node around every match of `regex`, replacing the matches with the HTML
+/// returned by `f`
+fn replace_in_text<'a, F>(
+    arena: &'a Arena<AstNode<'a>>,
+    node: &'a AstNode<'a>,
+    regex: &regex::Regex,
+    mut f: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(&regex::Captures) -> Result<String, Box<dyn std::error::Error>>,
+{
+    let text = match &node.data.borrow().value {
+        NodeValue::Text(t) => String::from_utf8_lossy(t).into_owned(),
+        _ => return Ok(()),
+    };
+    if !regex.is_match(&text) {
+        return Ok(());
+    }
 
+    let new_node = |value: NodeValue| {
+        arena.alloc(comrak::arena_tree::Node::new(RefCell::new(Ast::new(value))))
+    };
+    let mut last = 0;
+    for caps in regex.captures_iter(&text) {
+        let m = caps.get(0).expect("match");
+        if m.start() > last {
+            let before = text[last..m.start()].as_bytes().to_vec();
+            node.insert_before(new_node(NodeValue::Text(before)));
+        }
+        let html = f(&caps)?;
+        node.insert_before(new_node(NodeValue::HtmlInline(html.into_bytes())));
+        last = m.end();
+    }
+    node.data.borrow_mut().value = NodeValue::Text(text[last..].as_bytes().to_vec());
+    Ok(())
+}
+
+/// parse a post and pull out all of its fenced code blocks (with includes resolved), so that
+/// they can be checked without rendering the post
+pub fn extract_code_blocks(
+    src: &str,
+    options: &FormatOptions,
+) -> Result<Vec<CodeBlock>, Box<dyn std::error::Error>> {
+    let arena = Arena::new();
+    let root = parse_document(&arena, src, &COMRAK_OPTIONS);
+
+    let mut blocks: Vec<CodeBlock> = Vec::default();
+    for node in root.descendants() {
+        let ast = node.data.borrow();
+        if let NodeValue::CodeBlock(block) = &ast.value {
+            let info = CodeInfo::parse(&String::from_utf8_lossy(block.info.as_ref()))?;
+            let source = match resolve_include(&info, &options.source)? {
+                Some(included) => included,
+                None => String::from_utf8_lossy(block.literal.as_ref()).into_owned(),
+            };
+            blocks.push(CodeBlock {
+                info,
+                source,
+                line: ast.start_line as usize + options.line_offset,
+            });
+        }
+    }
+    Ok(blocks)
+}
+
+pub fn format_markdown(
+    src: &str,
+    options: &FormatOptions,
+) -> Result<FormatResponse, Box<dyn std::error::Error>> {
     let arena = Arena::new();
+    let src = expand_shortcodes(src, options)?;
+    let src = mark_containers(&src);
+    let src = src.as_str();
+    // equations are numbered before inline math is rendered, so it can refer to them
+    let equations = Equations::scan(parse_document(&arena, src, &COMRAK_OPTIONS))?;
 
     // parse math
     // TODO: move into markdown only when in paragraphs
     let mut found_inline_tex: bool = false;
-    let src =
-        INLINE_MATH_REGEX.replace_all(src, |caps: &regex::Captures| {
-            match create_katex_inline(caps.get(2).expect("3 capture groups").as_str()) {
-                Ok(s) => {
-                    found_inline_tex = true;
-                    s.trim().to_owned()
-                }
-                Err(e) => {
-                    let s = caps.get(2).expect("3 capture groups").as_str().to_owned();
-                    eprintln!("Failed to parse `{}` as inline KaTeX: {:?}", s, e);
-                    s
+    let mut inline_error: Option<Box<dyn std::error::Error>> = None;
+    let src = INLINE_MATH_REGEX.replace_all(src, |caps: &regex::Captures| {
+        let math = caps.get(2).expect("3 capture groups");
+        match create_katex_inline(math.as_str(), &options.math_macros, &equations) {
+            Ok(s) => {
+                found_inline_tex = true;
+                s.trim().to_owned()
+            }
+            Err(e) => {
+                let line = src[..math.start()].matches('\n').count() + 1;
+                match options.render_error(line, "inline math", true, e) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        inline_error = Some(e);
+                        String::default()
+                    }
                 }
             }
-        });
+        }
+    });
+    if let Some(e) = inline_error {
+        return Err(e);
+    }
 
     let root = parse_document(&arena, src.as_ref(), &COMRAK_OPTIONS);
+    merge_text_nodes(root);
+    convert_alert_blockquotes(&arena, root);
+    let include_tabs_script = render_containers(&arena, root, options)?;
+    resolve_post_links(root, options)?;
+    let mut attribute_lists = collect_attribute_lists(root)?;
+    let numbering = Numbering::scan(root, &attribute_lists, equations, options)?;
+    number_tables(&arena, &mut attribute_lists, &numbering);
 
     fn iter_nodes<'a, F>(node: &'a AstNode<'a>, f: &mut F) -> Result<(), Box<dyn std::error::Error>>
     where
@@ -126,19 +513,39 @@ pub fn format_markdown(src: &str) -> Result<FormatResponse, Box<dyn std::error::
     }
 
     let mut use_katex_css = found_inline_tex;
+    let mut use_placeholders = false;
     iter_nodes(root, &mut |node| {
+        let line = source_line(node);
         let value = &mut node.data.borrow_mut().value;
         match value {
             NodeValue::CodeBlock(ref block) => {
-                let lang = String::from_utf8_lossy(block.info.as_ref());
+                let info = String::from_utf8_lossy(block.info.as_ref());
                 let source = String::from_utf8_lossy(block.literal.as_ref());
-                let FormatResponse {
-                    output,
-                    include_katex_css,
-                } = format_code(&lang, &source)?;
-                if include_katex_css {
-                    use_katex_css = true;
-                }
+                let formatted = CodeInfo::parse(&info).and_then(|mut info| {
+                    // numbered blocks without an `id` use the one made up for them
+                    if let Some(label) = numbering.label(node) {
+                        info.attributes.insert("id".to_owned(), label.to_owned());
+                    }
+                    match resolve_include(&info, &options.source)? {
+                        Some(included) => format_code(&info, &included, options, &numbering),
+                        None => format_code(&info, &source, options, &numbering),
+                    }
+                });
+                let output = match formatted {
+                    Ok(FormatResponse {
+                        output,
+                        include_katex_css,
+                        ..
+                    }) => {
+                        if include_katex_css {
+                            use_katex_css = true;
+                        }
+                        output
+                    }
+                    Err(e) => {
+                        options.render_error(line, &format!("`{}` code block", info), false, e)?
+                    }
+                };
                 let highlighted: Vec<u8> = Vec::from(output.into_bytes());
                 *value = NodeValue::HtmlInline(highlighted);
             }
@@ -156,7 +563,27 @@ pub fn format_markdown(src: &str) -> Result<FormatResponse, Box<dyn std::error::
                                 child.detach();
                             }
                             first_child.detach();
-                            let figure = wrap_image_in_figure(&link, &alt)?;
+                            let attributes = attribute_lists
+                                .iter()
+                                .find(|(n, _)| std::ptr::eq(*n, *first_child))
+                                .map(|(_, a)| a);
+                            let preview = if options.images.placeholders {
+                                placeholder(&String::from_utf8_lossy(&link.url))
+                            } else {
+                                None
+                            };
+                            if preview.is_some() {
+                                use_placeholders = true;
+                            }
+                            let figure = wrap_image_in_figure(
+                                &link,
+                                &alt,
+                                numbering.label(first_child),
+                                attributes,
+                                &numbering,
+                                &options.images,
+                                preview.as_deref(),
+                            )?;
                             let figure: Vec<u8> = Vec::from(figure.into_bytes());
                             *value = NodeValue::HtmlInline(figure);
                         }
@@ -185,11 +612,220 @@ pub fn format_markdown(src: &str) -> Result<FormatResponse, Box<dyn std::error::
         Ok(())
     })?;
 
+    // link `[[slug]]` and `[[Post Title]]` wiki links to the posts they name
+    let texts: Vec<&AstNode> = root
+        .descendants()
+        .filter(|node| matches!(node.data.borrow().value, NodeValue::Text(_)))
+        .collect();
+    for text in texts {
+        let line = source_line(text);
+        replace_in_text(&arena, text, &WIKI_LINK_REGEX, |caps| {
+            wiki_link_html(caps, &options.posts)
+                .or_else(|e| options.render_error(line, "wiki link", true, e))
+        })?;
+    }
+
+    // link `@fig:label` style references to what they reference, flagging any to missing labels
+    let texts: Vec<&AstNode> = root
+        .descendants()
+        .filter(|node| matches!(node.data.borrow().value, NodeValue::Text(_)))
+        .collect();
+    for text in texts {
+        let line = source_line(text);
+        // a reference to nothing is always an error, strict or not
+        replace_in_text(&arena, text, &CROSSREF_REGEX, |caps| {
+            numbering
+                .ref_html(caps)
+                .map_err(|e| -> Box<dyn std::error::Error> {
+                    Box::from(format!(
+                        "{}:{}: {}",
+                        options.source.display(),
+                        line + options.line_offset,
+                        e
+                    ))
+                })
+        })?;
+    }
+
+    // resolve `[@key]` citations against the post's bibliography, listing what was cited
+    if let Some(path) = &options.bibliography {
+        let mut bibliography = Bibliography::load(path, options.citation_style)?;
+        let texts: Vec<&AstNode> = root
+            .descendants()
+            .filter(|node| matches!(node.data.borrow().value, NodeValue::Text(_)))
+            .collect();
+        for text in texts {
+            let line = source_line(text);
+            replace_in_text(&arena, text, &CITATION_REGEX, |caps| {
+                bibliography
+                    .cite(caps)
+                    .or_else(|e| options.render_error(line, "citation", true, e))
+            })?;
+        }
+        if let Some(references) = bibliography.references_html() {
+            let references = arena.alloc(comrak::arena_tree::Node::new(RefCell::new(Ast::new(
+                NodeValue::HtmlInline(references.into_bytes()),
+            ))));
+            // before the footnotes, which comrak puts at the end of the document
+            let footnotes = root
+                .children()
+                .find(|node| matches!(node.data.borrow().value, NodeValue::FootnoteDefinition(_)));
+            match footnotes {
+                Some(footnotes) => footnotes.insert_before(references),
+                None => root.append(references),
+            }
+        }
+    }
+
+    // before the attribute lists, which render their nodes' footnote references into html
+    if options.sidenotes {
+        convert_footnotes_to_sidenotes(&arena, root, &COMRAK_OPTIONS)?;
+    }
+    apply_attribute_lists(&arena, &attribute_lists, &COMRAK_OPTIONS)?;
+
     let mut output: Vec<u8> = Vec::with_capacity((src.len() as f64 * 1.2) as usize);
     format_html(root, &COMRAK_OPTIONS, &mut output).expect("can format HTML");
     let output = String::from_utf8(output).expect("valid utf-8 generated HTML");
+    let output = inject_heading_attributes(&output, &attribute_lists);
+    let output = add_image_attributes(&output);
+    let output = prefix_inline_svg_ids(&output);
     Ok(FormatResponse {
         output,
         include_katex_css: use_katex_css,
+        include_tabs_script,
+        include_placeholder_script: use_placeholders,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_fenced_code_blocks() {
+        let src = "text\n```rust\n{{< kbd >}}\n```\nmore\n~~~~\ncode\n~~~~\n";
+        let ranges = fenced_ranges(src);
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(&src[ranges[0].clone()], "```rust\n{{< kbd >}}\n```\n");
+        assert_eq!(&src[ranges[1].clone()], "~~~~\ncode\n~~~~\n");
+    }
+
+    #[test]
+    fn fences_only_close_with_a_long_enough_fence_of_the_same_kind() {
+        let src = "````\n```\n~~~~\n````\nafter\n";
+        let ranges = fenced_ranges(src);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(&src[ranges[0].clone()], "````\n```\n~~~~\n````\n");
+    }
+
+    #[test]
+    fn fences_with_info_after_them_dont_close_blocks() {
+        let src = "```\n``` rust\n```\n";
+        assert_eq!(fenced_ranges(src), vec![0..src.len()]);
+    }
+
+    #[test]
+    fn indented_code_isnt_a_fence() {
+        assert!(fenced_ranges("    ```\n    code\n    ```\n").is_empty());
+    }
+
+    #[test]
+    fn unclosed_fences_run_to_the_end() {
+        let src = "text\n```\ncode\n";
+        assert_eq!(fenced_ranges(src), vec![5..src.len()]);
+    }
+
+    fn scan_numbering<'a>(
+        arena: &'a Arena<AstNode<'a>>,
+        src: &str,
+        options: &FormatOptions,
+    ) -> Result<(&'a AstNode<'a>, Numbering), Box<dyn std::error::Error>> {
+        let root = parse_document(arena, src, &COMRAK_OPTIONS);
+        let attribute_lists = collect_attribute_lists(root)?;
+        let numbering = Numbering::scan(root, &attribute_lists, Equations::default(), options)?;
+        Ok((root, numbering))
+    }
+
+    #[test]
+    fn numbers_everything_captioned_or_labelled() {
+        let src = r#"![captioned](a.png "A caption")
+
+![labelled](b.png){#fig:b}
+
+![neither](c.png)
+
+Inline ![images](d.png "Aren't figures") aren't numbered.
+
+```rust caption="Some code"
+fn main() {}
+```
+
+```rust
+fn unnumbered() {}
+```
+
+```dot caption="A graph"
+digraph {}
+```
+
+| a |
+|---|
+| 1 |
+
+{caption="Numbers"}
+"#;
+        let arena = Arena::new();
+        let (root, numbering) = scan_numbering(&arena, src, &FormatOptions::default()).unwrap();
+        let labels: Vec<&str> = root
+            .descendants()
+            .filter_map(|node| numbering.label(node))
+            .collect();
+        assert_eq!(labels, vec!["fig-1", "fig:b", "lst-1", "fig-3", "tbl-1"]);
+        assert_eq!(numbering.name("fig-1").as_deref(), Some("Figure 1"));
+        assert_eq!(numbering.name("fig:b").as_deref(), Some("Figure 2"));
+        assert_eq!(numbering.name("lst-1").as_deref(), Some("Listing 1"));
+        assert_eq!(numbering.name("fig-3").as_deref(), Some("Figure 3"));
+        assert_eq!(numbering.name("tbl-1").as_deref(), Some("Table 1"));
+    }
+
+    #[test]
+    fn duplicate_labels_are_errors() {
+        let src = "![a](a.png){#fig:a}\n\n![b](b.png){#fig:a}\n\n![c](c.png \"C\")\n";
+        let arena = Arena::new();
+        let options = FormatOptions::default();
+        let (_, numbering) = scan_numbering(&arena, src, &options).unwrap();
+        // the duplicate isn't numbered, and doesn't use up a number
+        assert_eq!(numbering.name("fig:a").as_deref(), Some("Figure 1"));
+        assert_eq!(numbering.name("fig-2").as_deref(), Some("Figure 2"));
+
+        let strict = FormatOptions {
+            strict: true,
+            ..FormatOptions::default()
+        };
+        assert!(scan_numbering(&Arena::new(), src, &strict).is_err());
+    }
+
+    #[test]
+    fn sidenotes_in_nodes_with_attribute_lists() {
+        let options = FormatOptions {
+            sidenotes: true,
+            ..FormatOptions::default()
+        };
+        let src = "A claim[^1].\n{.lead}\n\n[^1]: The source.\n";
+        let output = format_markdown(src, &options).unwrap().output;
+        assert!(
+            output.contains(r#"<p class="lead">A claim<label for="sidenote-1""#),
+            "{}",
+            output
+        );
+        assert!(output.contains("The source.</span>.</p>"), "{}", output);
+        assert!(!output.contains("footnote"), "{}", output);
+    }
+
+    #[test]
+    fn unknown_references_are_errors_even_when_not_strict() {
+        let options = FormatOptions::default();
+        assert!(format_markdown("See @fig:missing.\n", &options).is_err());
+        assert!(format_markdown("See @eq:missing.\n", &options).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use super::tool::{run_tool, TempDir};
+use crate::cache::{cached, hash_key};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static RENDER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref THEME_COLOUR_REGEX: regex::Regex = regex::Regex::new(r#"--theme-([\w-]+)\s*:\s*(#[0-9a-fA-F]{3,8})\s*;"#).expect("valid regex");
+    static ref DARK_THEME: String = dark_theme(include_str!("../../style/variables.scss"));
+}
+
+const LIGHT_THEME: &str = r##"{"theme":"neutral"}"##;
+
+/// which of the `--theme-*` colours each of mermaid's theme variables takes
+const DARK_THEME_COLOURS: &[(&str, &str)] = &[
+    ("background", "background"),
+    ("primaryColor", "currentline"),
+    ("primaryTextColor", "foreground"),
+    ("primaryBorderColor", "purple"),
+    ("secondaryColor", "comment"),
+    ("tertiaryColor", "code-background"),
+    ("lineColor", "cyan"),
+    ("textColor", "foreground"),
+    ("noteBkgColor", "comment"),
+    ("noteTextColor", "foreground"),
+    ("noteBorderColor", "purple"),
+];
+
+/// mermaid's config for the dark palette in style/variables.scss, the same one that
+/// style/dark-mode.scss uses
+fn dark_theme(variables: &str) -> String {
+    let colours: HashMap<&str, &str> = THEME_COLOUR_REGEX
+        .captures_iter(variables)
+        .map(|caps| {
+            (
+                caps.get(1).expect("2 capture groups").as_str(),
+                caps.get(2).expect("2 capture groups").as_str(),
+            )
+        })
+        .collect();
+    let theme_variables: Vec<String> = DARK_THEME_COLOURS
+        .iter()
+        .map(|(key, name)| {
+            let colour = colours
+                .get(name)
+                .unwrap_or_else(|| panic!("style/variables.scss defines `--theme-{}`", name));
+            format!(r#""{}":"{}""#, key, colour)
+        })
+        .collect();
+    format!(
+        r#"{{"theme":"base","themeVariables":{{"darkMode":true,{}}}}}"#,
+        theme_variables.join(",")
+    )
+}
+
+fn render_mermaid(src: &str, theme: &str, id: &str) -> Result<String, Box<dyn std::error::Error>> {
+    use std::process::Command;
+
+    // posts render in parallel and may share a diagram, so give each render its own directory
+    let temp = TempDir::new(&format!(
+        "blogham-{}-{}-{}",
+        id,
+        std::process::id(),
+        RENDER_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ))?;
+    let dir = temp.path();
+    let input = dir.join("diagram.mmd");
+    let config = dir.join("config.json");
+    let output = dir.join("diagram.svg");
+    std::fs::write(&input, src)?;
+    std::fs::write(&config, theme)?;
+
+    let result = run_tool(
+        Command::new("mmdc")
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--configFile")
+            .arg(&config)
+            .arg("--backgroundColor")
+            .arg("transparent")
+            .arg("--svgId")
+            .arg(id)
+            .arg("--quiet"),
+        "",
+    )
+    .and_then(|_| Ok(std::fs::read_to_string(&output)?));
+    result
+}
+
+/// render a mermaid diagram into a light and a dark svg, the stylesheet shows whichever
+/// matches the reader's colour scheme
+pub fn create_mermaid_svg(src: &str) -> Result<String, Box<dyn std::error::Error>> {
+    // mermaid scopes its styles to the svg's id, so each render needs a unique one
+    let id = format!("mermaid-{}", hash_key(src));
+
+    let light_id = format!("{}-light", id);
+    let light = cached("mermaid", &format!("{}\n{}", LIGHT_THEME, src), || {
+        render_mermaid(src, LIGHT_THEME, &light_id)
+    })?;
+    let dark_id = format!("{}-dark", id);
+    let dark = cached("mermaid", &format!("{}\n{}", *DARK_THEME, src), || {
+        render_mermaid(src, &DARK_THEME, &dark_id)
+    })?;
+
+    Ok(format!(
+        r#"<div class="mermaid-light">{}</div><div class="mermaid-dark">{}</div>"#,
+        light, dark
+    ))
+}
@@ -1,12 +1,32 @@
+use super::config::Config;
 use super::frontmatter::{FrontMatter, RawFrontMatter};
+pub use links::{linked_posts, LinkTarget};
 use serde::Serialize;
+pub use snippets::TestResults;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use tera::Tera;
 
+mod admonitions;
+mod attributes;
+mod bibliography;
+mod codeinfo;
+mod containers;
+mod graphviz;
+mod include;
 mod katex;
+mod links;
 pub mod markdown;
+mod mermaid;
+mod numbering;
 mod plantuml;
 mod pygments;
+mod shortcodes;
+mod sidenotes;
+mod snippets;
+pub mod svg;
+mod tikz;
+mod tool;
 
 lazy_static::lazy_static! {
     pub static ref TEMPLATES: Tera = {
@@ -28,43 +48,47 @@ pub struct Post {
     pub source: PathBuf,
     pub url: String,
     pub contents: String,
+    /// number of lines before `contents` starts in the source file
+    pub line_offset: usize,
 }
 
 impl Post {
     fn extract_frontmatter(
         src: &str,
-    ) -> Result<(Option<RawFrontMatter>, String), Box<dyn std::error::Error>> {
+    ) -> Result<(Option<RawFrontMatter>, String, usize), Box<dyn std::error::Error>> {
         if src.starts_with("---\n") {
             let slice = &src[4..];
             let end = slice.find("---\n");
             if end.is_none() {
-                return Ok((None, src.to_owned()));
+                return Ok((None, src.to_owned(), 0));
             }
             let end = end.unwrap();
             let front = &slice[..end];
             let contents = &slice[end + 4..];
+            let line_offset = front.lines().count() + 2;
             let front: RawFrontMatter = serde_yaml::from_str(front)?;
-            Ok((Some(front), contents.to_owned()))
+            Ok((Some(front), contents.to_owned(), line_offset))
         } else if src.starts_with("---\r\n") {
             let slice = &src[5..];
             let end = slice.find("---\r\n");
             if end.is_none() {
-                return Ok((None, src.to_owned()));
+                return Ok((None, src.to_owned(), 0));
             }
             let end = end.unwrap();
             let front = &slice[..end];
             let contents = &slice[end + 5..];
+            let line_offset = front.lines().count() + 2;
             let front: RawFrontMatter = serde_yaml::from_str(front)?;
-            Ok((Some(front), contents.to_owned()))
+            Ok((Some(front), contents.to_owned(), line_offset))
         } else {
-            Ok((None, src.to_owned()))
+            Ok((None, src.to_owned(), 0))
         }
     }
 
     pub fn load<P: AsRef<Path>>(src: P) -> Result<Option<Post>, Box<dyn std::error::Error>> {
         let contents = std::fs::read_to_string(src.as_ref())?;
 
-        let (front, contents) = Post::extract_frontmatter(&contents)?;
+        let (front, contents, line_offset) = Post::extract_frontmatter(&contents)?;
         if front.is_none() {
             eprintln!(
                 "skipping `{}` as it contains invalid metadata",
@@ -96,24 +120,94 @@ impl Post {
             contents,
             source: src.as_ref().to_owned(),
             url,
+            line_offset,
         }))
     }
 
+    /// where each post is published, keyed by the name of its source file
+    pub fn link_targets(posts: &[Post]) -> BTreeMap<String, LinkTarget> {
+        posts
+            .iter()
+            .filter_map(|post| {
+                let name = post.source.file_name()?.to_str()?.to_owned();
+                Some((
+                    name,
+                    LinkTarget {
+                        url: post.url.clone(),
+                        title: post.front.title.clone(),
+                        slug: post.front.slug.clone(),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    fn format_options(
+        &self,
+        config: &Config,
+        posts: &BTreeMap<String, LinkTarget>,
+    ) -> markdown::FormatOptions {
+        let mut math_macros = config.math_macros.clone();
+        math_macros.extend(self.front.math_macros.clone());
+        markdown::FormatOptions {
+            source: self.source.clone(),
+            line_offset: self.line_offset,
+            strict: config.strict,
+            math_macros,
+            tests: config.tests.clone(),
+            sidenotes: self.front.sidenotes.unwrap_or(config.sidenotes),
+            bibliography: self.front.bibliography.as_ref().map(|bibliography| {
+                self.source
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .join(bibliography)
+            }),
+            citation_style: self.front.citation_style.unwrap_or(config.citation_style),
+            posts: posts.clone(),
+            images: config.images.clone(),
+        }
+    }
+
+    /// compile / run all of the code blocks in the post marked `test`
+    pub fn test(&self, config: &Config) -> Result<TestResults, Box<dyn std::error::Error>> {
+        let options = self.format_options(config, &BTreeMap::default());
+        let blocks = markdown::extract_code_blocks(&self.contents, &options)?;
+        Ok(snippets::test_code_blocks(
+            blocks,
+            &self.source,
+            &config.tests,
+        ))
+    }
+
+    /// render the post's markdown into html
+    pub fn format(
+        &self,
+        config: &Config,
+        posts: &BTreeMap<String, LinkTarget>,
+    ) -> Result<markdown::FormatResponse, Box<dyn std::error::Error>> {
+        let options = self.format_options(config, posts);
+        markdown::format_markdown(&self.contents, &options)
+    }
+
+    /// render the post's page from its formatted markdown, listing the posts that link to it
     pub fn render(
         &self,
+        formatted: &markdown::FormatResponse,
+        backlinks: &[LinkTarget],
         style: &str,
         katex_style: &str,
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let markdown::FormatResponse {
-            output,
-            include_katex_css,
-        } = markdown::format_markdown(&self.contents)?;
-
         let mut context = tera::Context::new();
         context.insert("title", &self.front.title);
         context.insert("front", &self.front);
-        context.insert("content", &output);
-        context.insert("include_katex_css", &include_katex_css);
+        context.insert("content", &formatted.output);
+        context.insert("include_katex_css", &formatted.include_katex_css);
+        context.insert("include_tabs_script", &formatted.include_tabs_script);
+        context.insert(
+            "include_placeholder_script",
+            &formatted.include_placeholder_script,
+        );
+        context.insert("backlinks", backlinks);
         context.insert("style", style);
         context.insert("katex_style", katex_style);
 
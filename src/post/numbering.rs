@@ -0,0 +1,242 @@
+use super::attributes::Attributes;
+use super::codeinfo::CodeInfo;
+use super::katex::Equations;
+use super::markdown::{escape_html, source_line, FormatOptions};
+use comrak::nodes::{Ast, AstNode, NodeHtmlBlock, NodeValue};
+use comrak::Arena;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+lazy_static::lazy_static! {
+    /// `@fig:label`, `@tbl:label`, `@lst:label` or `@eq:label`, along with the character before
+    /// it so that email addresses aren't mistaken for references
+    pub static ref CROSSREF_REGEX: regex::Regex = regex::Regex::new(r#"(^|[^\w@])@(fig|tbl|lst|eq):([\w-]+)"#).expect("valid regex");
+}
+
+/// what a label's prefix says it is labelling, i.e. `fig:schematic` labels a figure
+fn kind_name(label: &str) -> Option<&'static str> {
+    match label.split(':').next() {
+        Some("fig") if label.len() > 4 => Some("Figure"),
+        Some("tbl") if label.len() > 4 => Some("Table"),
+        Some("lst") if label.len() > 4 => Some("Listing"),
+        _ => None,
+    }
+}
+
+/// the prefix of the ids made up for unlabelled things of a kind, i.e. `fig-3`
+fn id_prefix(kind: &str) -> &'static str {
+    match kind {
+        "Figure" => "fig",
+        "Table" => "tbl",
+        _ => "lst",
+    }
+}
+
+/// the code blocks that are rendered into figures rather than listings
+const DIAGRAM_LANGUAGES: &[&str] = &["plantuml", "dot", "graphviz", "mermaid", "tikz"];
+
+/// whether a node is an image that is rendered as a figure, i.e. it is alone in its paragraph
+fn is_figure_image<'a>(node: &'a AstNode<'a>) -> bool {
+    let parent = match node.parent() {
+        Some(parent) => parent,
+        None => return false,
+    };
+    matches!(parent.data.borrow().value, NodeValue::Paragraph)
+        && parent.children().count() == 1
+        && node.children().count() > 0
+}
+
+/// Numbers for a post's figures, tables and code listings, counted separately for each kind in
+/// the order they appear in the post, along with the numbers of its equations
+pub struct Numbering {
+    /// the kind and number of everything numbered, by its label
+    numbers: HashMap<String, (&'static str, usize)>,
+    /// the label of every numbered node, by the node's address
+    labels: HashMap<usize, String>,
+    pub equations: Equations,
+}
+
+impl Numbering {
+    /// Number everything with a `fig:`, `tbl:` or `lst:` label or a caption: images (rendered as
+    /// figures) and tables labelled by their attribute lists (`{#fig:schematic}`), and code
+    /// blocks labelled with an `id` (```` ```dot id=fig:pipeline ````). Anything numbered
+    /// without a label is given an id from its number, i.e. `fig-3`.
+    pub fn scan<'a>(
+        root: &'a AstNode<'a>,
+        attribute_lists: &[(&'a AstNode<'a>, Attributes)],
+        equations: Equations,
+        options: &FormatOptions,
+    ) -> Result<Numbering, Box<dyn std::error::Error>> {
+        let mut numbers: HashMap<String, (&'static str, usize)> = HashMap::default();
+        let mut labels: HashMap<usize, String> = HashMap::default();
+        let mut counts: HashMap<&'static str, usize> = HashMap::default();
+        for node in root.descendants() {
+            let attributes = || {
+                attribute_lists
+                    .iter()
+                    .find(|(n, _)| std::ptr::eq(*n, node))
+                    .map(|(_, a)| a)
+            };
+            let (kind, label, captioned) = match &node.data.borrow().value {
+                NodeValue::Image(link) if is_figure_image(node) => (
+                    "Figure",
+                    attributes().and_then(|a| a.id.clone()),
+                    !link.title.is_empty(),
+                ),
+                NodeValue::Table(_) => (
+                    "Table",
+                    attributes().and_then(|a| a.id.clone()),
+                    attributes().map_or(false, |a| a.pairs.iter().any(|(k, _)| k == "caption")),
+                ),
+                NodeValue::CodeBlock(block) => {
+                    let info = match CodeInfo::parse(&String::from_utf8_lossy(&block.info)) {
+                        Ok(info) if info.lang != "katex" => info,
+                        _ => continue,
+                    };
+                    let kind = if DIAGRAM_LANGUAGES.contains(&info.lang.as_str()) {
+                        "Figure"
+                    } else {
+                        "Listing"
+                    };
+                    (kind, info.get("id").map(str::to_owned), info.has("caption"))
+                }
+                _ => continue,
+            };
+            let kind = match label.as_deref().and_then(kind_name) {
+                Some(kind) => kind,
+                None if captioned => kind,
+                None => continue,
+            };
+
+            let number = counts.get(kind).copied().unwrap_or_default() + 1;
+            let label = label.unwrap_or_else(|| format!("{}-{}", id_prefix(kind), number));
+            if numbers.contains_key(&label) {
+                options.render_error(
+                    source_line(node),
+                    "label",
+                    true,
+                    Box::from(format!("label `{}` is used more than once", label)),
+                )?;
+                continue;
+            }
+            counts.insert(kind, number);
+            numbers.insert(label.clone(), (kind, number));
+            labels.insert(node as *const AstNode<'a> as usize, label);
+        }
+        Ok(Numbering {
+            numbers,
+            labels,
+            equations,
+        })
+    }
+
+    /// the label of a numbered node, which it was either given or had made up for it
+    pub fn label<'a>(&self, node: &'a AstNode<'a>) -> Option<&str> {
+        self.labels
+            .get(&(node as *const AstNode<'a> as usize))
+            .map(String::as_str)
+    }
+
+    /// `Figure 3` for a numbered label
+    pub fn name(&self, label: &str) -> Option<String> {
+        let (kind, number) = self.numbers.get(label)?;
+        Some(format!("{} {}", kind, number))
+    }
+
+    /// The contents of a figcaption for something that might be numbered and might have a caption
+    /// (already escaped), i.e. `Figure 3: A schematic`
+    pub fn caption_html(&self, label: Option<&str>, caption: Option<&str>) -> Option<String> {
+        match (label.and_then(|l| self.name(l)), caption) {
+            (Some(name), Some(caption)) => Some(format!(
+                r#"<span class="caption-number">{}:</span> {}"#,
+                name, caption
+            )),
+            (Some(name), None) => Some(format!(r#"<span class="caption-number">{}</span>"#, name)),
+            (None, caption) => caption.map(str::to_owned),
+        }
+    }
+
+    /// turn a `@fig:label` match from the prose into a link to what it references
+    pub fn ref_html(&self, caps: &regex::Captures) -> Result<String, Box<dyn std::error::Error>> {
+        let before = escape_html(caps.get(1).expect("3 capture groups").as_str());
+        let kind = caps.get(2).expect("3 capture groups").as_str();
+        let name = caps.get(3).expect("3 capture groups").as_str();
+        let label = format!("{}:{}", kind, name);
+
+        if kind == "eq" {
+            // equations can be labelled with or without the `eq:` prefix
+            let label = if self.equations.number(&label).is_ok() {
+                label.as_str()
+            } else {
+                name
+            };
+            let number = self.equations.number(label).map_err(|_| {
+                format!(
+                    "reference to unknown equation `@eq:{}` (label it with `\\label{{eq:{}}}`)",
+                    name, name
+                )
+            })?;
+            return Ok(format!(
+                r##"{}<a class="crossref" href="#{}">Equation ({})</a>"##,
+                before,
+                Equations::anchor(label),
+                number
+            ));
+        }
+
+        match self.name(&label) {
+            Some(text) => Ok(format!(
+                r##"{}<a class="crossref" href="#{}">{}</a>"##,
+                before,
+                escape_html(&label),
+                text
+            )),
+            None => Err(Box::from(format!(
+                "reference to unknown label `@{}`, nothing is labelled with `{{#{}}}` or `id={}`",
+                label, label, label
+            ))),
+        }
+    }
+}
+
+/// Wrap numbered tables in a figure carrying the label and a numbered caption, with any
+/// `caption="..."` from the table's attribute list
+pub fn number_tables<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    attribute_lists: &mut [(&'a AstNode<'a>, Attributes)],
+    numbering: &Numbering,
+) {
+    let new_node = |html: String| {
+        arena.alloc(comrak::arena_tree::Node::new(RefCell::new(Ast::new(
+            NodeValue::HtmlBlock(NodeHtmlBlock {
+                block_type: 0,
+                literal: html.into_bytes(),
+            }),
+        ))))
+    };
+    for (node, attributes) in attribute_lists.iter_mut() {
+        if !matches!(node.data.borrow().value, NodeValue::Table(_)) {
+            continue;
+        }
+        let label = match numbering.label(node) {
+            Some(label) => label.to_owned(),
+            None => continue,
+        };
+        let caption = attributes
+            .pairs
+            .iter()
+            .position(|(key, _)| key == "caption")
+            .map(|i| escape_html(&attributes.pairs.remove(i).1));
+        attributes.id = None;
+
+        let caption = numbering
+            .caption_html(Some(&label), caption.as_deref())
+            .unwrap_or_default();
+        node.insert_before(new_node(format!(
+            r#"<figure class="table" id="{}"><figcaption>{}</figcaption>"#,
+            escape_html(&label),
+            caption
+        )));
+        node.insert_after(new_node("</figure>".to_owned()));
+    }
+}
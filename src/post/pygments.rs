@@ -1,43 +1,134 @@
-pub fn create_code_block(src: &str, lang: &str) -> Result<String, Box<dyn std::error::Error>> {
-    use std::io::Write;
-    use std::process::{Command, Stdio};
-
-    let mut child = match Command::new("pygmentize")
-        .arg("-l")
-        .arg(lang)
-        .arg("-f")
-        .arg("html")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-    {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!(
-                "failed to launch pygmentize, not rendering math block: {:?}",
-                e
-            );
-            return Err(Box::from(e));
+use super::codeinfo::CodeInfo;
+use super::markdown::escape_html;
+use super::tool::run_tool;
+
+lazy_static::lazy_static! {
+    static ref LINE_SPAN_REGEX: regex::Regex = regex::Regex::new(r#"<span id="line-(\d+)">"#).expect("valid regex");
+}
+
+#[derive(Clone, Copy)]
+enum DiffLine {
+    Added,
+    Removed,
+    Unchanged,
+}
+
+impl DiffLine {
+    fn span(&self) -> &'static str {
+        match self {
+            DiffLine::Added => r#"<span class="line diff-added" data-diff="+">"#,
+            DiffLine::Removed => r#"<span class="line diff-removed" data-diff="-">"#,
+            DiffLine::Unchanged => r#"<span class="line" data-diff=" ">"#,
         }
+    }
+}
+
+/// strip the `+` / `-` / ` ` markers from the start of each line of a diff, returning the
+/// underlying source and what happened to each line
+fn split_diff(src: &str) -> (String, Vec<DiffLine>) {
+    let mut source = String::with_capacity(src.len());
+    let mut lines: Vec<DiffLine> = Vec::default();
+    for line in src.lines() {
+        let (kind, line) = match line.chars().next() {
+            Some('+') => (DiffLine::Added, &line[1..]),
+            Some('-') => (DiffLine::Removed, &line[1..]),
+            Some(' ') => (DiffLine::Unchanged, &line[1..]),
+            _ => (DiffLine::Unchanged, line),
+        };
+        lines.push(kind);
+        source.push_str(line);
+        source.push('\n');
+    }
+    (source, lines)
+}
+
+pub fn create_code_block(src: &str, info: &CodeInfo) -> Result<String, Box<dyn std::error::Error>> {
+    use std::process::Command;
+
+    let src = src.replace("\t", "    ");
+
+    // `diff-rust` or `rust diff` highlights rust while also marking the diff
+    let (lang, diff) = match info.lang.strip_prefix("diff-") {
+        Some(lang) => (lang, true),
+        None => (info.lang.as_str(), info.has("diff")),
+    };
+    let (src, diff_lines) = if diff {
+        let (src, lines) = split_diff(&src);
+        (src, Some(lines))
+    } else {
+        (src, None)
+    };
+
+    let html = if lang.is_empty() || info.has("nohighlight") {
+        let code = match &diff_lines {
+            Some(diff_lines) => src
+                .lines()
+                .zip(diff_lines.iter())
+                .map(|(line, kind)| format!("{}{}\n</span>", kind.span(), escape_html(line)))
+                .collect::<String>(),
+            None => escape_html(&src),
+        };
+        format!(
+            r#"<div class="highlight nohighlight"><pre><code>{}</code></pre></div>"#,
+            code
+        )
+    } else {
+        // pygments numbers the lines (and their spans) from here
+        let start_line: usize = match info.get("linenos") {
+            Some(start) if !start.is_empty() => start.parse().map_err(|_| {
+                format!(
+                    "invalid `linenos={}`, expected a starting line number",
+                    start
+                )
+            })?,
+            _ => 1,
+        };
+
+        let mut command = Command::new("pygmentize");
+        command.arg("-l").arg(lang).arg("-f").arg("html");
+        if info.has("linenos") {
+            command.arg("-O").arg("linenos=inline");
+            command.arg("-O").arg(format!("linenostart={}", start_line));
+        }
+        if let Some(lines) = info.line_ranges("hl_lines")? {
+            let lines: Vec<String> = lines.iter().map(usize::to_string).collect();
+            command
+                .arg("-O")
+                .arg(format!("hl_lines={}", lines.join(" ")));
+        }
+        if diff_lines.is_some() {
+            command.arg("-O").arg("linespans=line");
+        }
+        let html = run_tool(&mut command, &src)?;
+
+        // swap pygments' per-line spans for ones marking what the diff did to the line
+        match &diff_lines {
+            Some(diff_lines) => LINE_SPAN_REGEX
+                .replace_all(&html, |caps: &regex::Captures| {
+                    let line: usize = caps[1].parse().expect("line numbers are digits");
+                    line.checked_sub(start_line)
+                        .and_then(|i| diff_lines.get(i))
+                        .copied()
+                        .unwrap_or(DiffLine::Unchanged)
+                        .span()
+                })
+                .into_owned(),
+            None => html,
+        }
+    };
+    let html = if diff_lines.is_some() {
+        html.replacen(r#"class="highlight"#, r#"class="highlight diff"#, 1)
+    } else {
+        html
     };
 
-    let stdin = child.stdin.as_mut().expect("valid pygmentize stdin");
-    stdin.write_all(src.replace("\t", "    ").as_ref())?;
-
-    let output = child.wait_with_output()?;
-    if !output.status.success() {
-        eprintln!(
-            "failed to generate pygmentize, exit code: {:?}",
-            output.status.code()
-        );
-        eprintln!("pygmentize STDOUT:");
-        eprintln!("{}", String::from_utf8_lossy(output.stdout.as_ref()));
-        eprintln!("pygmentize STDERR:");
-        eprintln!("{}", String::from_utf8_lossy(output.stdout.as_ref()));
-        eprintln!("/pygmentize output");
-        return Err(Box::from("pygmentize failed"));
+    match info.get("title") {
+        Some(title) => Ok(format!(
+            r#"<figure class="code" data-lang="{}"><figcaption class="filename">{}</figcaption>{}</figure>"#,
+            escape_html(lang),
+            escape_html(title),
+            html
+        )),
+        None => Ok(html),
     }
-    let rendered: String = String::from_utf8(output.stdout)?;
-    Ok(rendered)
 }
@@ -0,0 +1,360 @@
+use super::codeinfo::{parse_attributes, split_tokens};
+use super::markdown::{fenced_ranges, FormatOptions};
+use super::TEMPLATES;
+use crate::config::Images;
+use crate::images::pictures;
+use std::ops::Range;
+
+lazy_static::lazy_static! {
+    static ref SHORTCODE_REGEX: regex::Regex = regex::Regex::new(r#"\{\{<\s*(/)?\s*([A-Za-z0-9_-]+)(.*?)(/)?\s*>\}\}"#).expect("valid regex");
+    static ref ESCAPED_SHORTCODE_REGEX: regex::Regex = regex::Regex::new(r#"\{\{</\*(.*?)\*/>\}\}"#).expect("valid regex");
+}
+
+/// stands in for the body while rendering a shortcode's template, so that the body can stay in
+/// the markdown and be formatted along with the rest of the post
+const BODY_MARKER: &str = "BLOGHAMSHORTCODEBODY";
+
+/// An html comment ends the html block it starts on the same line (it is CommonMark's html block
+/// type 2), so a block shortcode's opening and closing html can each sit on a single line and the
+/// body's markdown can start on the very next one. This keeps the expanded markdown to the same
+/// number of lines as the source, so that the line numbers in later errors stay right.
+const END_HTML_BLOCK: &str = "<!---->";
+
+struct Tag<'s> {
+    start: usize,
+    end: usize,
+    name: &'s str,
+    args: &'s str,
+    closing: bool,
+    self_closing: bool,
+}
+
+/// a shortcode that sits alone on its line(s) is a block, otherwise it is inline in a paragraph
+fn is_block(src: &str, start: usize, end: usize) -> bool {
+    let line_start = src[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = src[end..].find('\n').map(|i| end + i).unwrap_or(src.len());
+    src[line_start..start].trim().is_empty() && src[end..line_end].trim().is_empty()
+}
+
+fn describe_tera_error(error: tera::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(&error);
+    while let Some(s) = source {
+        message.push_str(": ");
+        message.push_str(&s.to_string());
+        source = s.source();
+    }
+    message
+}
+
+fn render_shortcode(
+    tag: &Tag,
+    body: Option<String>,
+    block: bool,
+    images: &Images,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let template = format!("shortcodes/{}.html", tag.name);
+    if !TEMPLATES.get_template_names().any(|t| t == template) {
+        return Err(Box::from(format!(
+            "unknown shortcode `{}` (there is no templates/{})",
+            tag.name, template
+        )));
+    }
+
+    let args = parse_attributes(
+        split_tokens(tag.args)
+            .map_err(|e| format!("bad arguments to shortcode `{}`: {}", tag.name, e))?,
+    );
+    let mut context = tera::Context::new();
+    for (key, value) in args.iter() {
+        context.insert(key.as_str(), value);
+    }
+    context.insert("args", &args);
+    if body.is_some() {
+        context.insert("body", BODY_MARKER);
+    }
+
+    let rendered = TEMPLATES.render(&template, &context).map_err(|e| {
+        format!(
+            "shortcode `{}` failed, check its arguments: {}",
+            tag.name,
+            describe_tera_error(e)
+        )
+    })?;
+    // images in shortcodes are served like the ones in the markdown
+    let rendered = pictures(&rendered, images);
+    // the shortcode takes a single line in the source, so its html does too
+    let rendered: Vec<&str> = rendered
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    let rendered = rendered.join(" ");
+
+    match body {
+        None => Ok(rendered),
+        Some(body) => {
+            let (before, after) = match rendered.find(BODY_MARKER) {
+                Some(i) => (&rendered[..i], &rendered[i + BODY_MARKER.len()..]),
+                None => (rendered.as_str(), ""),
+            };
+            if block {
+                // the html goes on the lines of the opening and closing tags, leaving the body's
+                // lines where they were so that it is parsed as markdown
+                let (leading, body, trailing) = split_blank_lines(&body);
+                Ok(format!(
+                    "{}{}{}{}{}{}{}",
+                    END_HTML_BLOCK,
+                    before.trim_end(),
+                    "\n".repeat(leading),
+                    body,
+                    "\n".repeat(trailing),
+                    END_HTML_BLOCK,
+                    after.trim_start()
+                ))
+            } else {
+                Ok(format!("{}{}{}", before, body, after))
+            }
+        }
+    }
+}
+
+/// split `text` into how many lines it starts with that are blank (including the rest of the line
+/// it starts on), the text between them, and how many lines it ends with that are blank
+fn split_blank_lines(text: &str) -> (usize, &str, usize) {
+    if text.trim().is_empty() {
+        return (text.matches('\n').count(), "", 0);
+    }
+    let start = text.len() - text.trim_start().len();
+    let start = text[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let end = text.trim_end().len();
+    let end = text[end..]
+        .find('\n')
+        .map(|i| end + i)
+        .unwrap_or(text.len());
+    (
+        text[..start].matches('\n').count(),
+        &text[start..end],
+        text[end..].matches('\n').count(),
+    )
+}
+
+/// find the tag closing `tags[open]`, if it has one
+fn find_closing_tag(tags: &[Tag], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, tag) in tags.iter().enumerate().skip(open + 1) {
+        if tag.name != tags[open].name {
+            continue;
+        }
+        if tag.closing {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        } else if !tag.self_closing {
+            depth += 1;
+        }
+    }
+    None
+}
+
+/// keep an error's html to the single line of the shortcode it replaces
+fn single_line(html: &str) -> String {
+    html.replace('\n', "&#10;")
+}
+
+/// copy `src[range]` into `output`, writing out the escaped shortcodes that aren't in fenced code
+fn push_source(output: &mut String, src: &str, range: Range<usize>, fences: &[Range<usize>]) {
+    let mut pos = range.start;
+    for caps in ESCAPED_SHORTCODE_REGEX.captures_iter(&src[range.clone()]) {
+        let m = caps.get(0).expect("match");
+        let start = range.start + m.start();
+        if fences.iter().any(|f| f.contains(&start)) {
+            continue;
+        }
+        output.push_str(&src[pos..start]);
+        output.push_str(&format!("{{{{<{}>}}}}", &caps[1]));
+        pos = range.start + m.end();
+    }
+    output.push_str(&src[pos..range.end]);
+}
+
+fn expand_tags(
+    src: &str,
+    tags: &[Tag],
+    range: Range<usize>,
+    fences: &[Range<usize>],
+    options: &FormatOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut output = String::with_capacity(range.len());
+    let mut pos = range.start;
+    let mut i = 0;
+    while i < tags.len() {
+        let tag = &tags[i];
+        push_source(&mut output, src, pos..tag.start, fences);
+        let line = src[..tag.start].matches('\n').count() + 1;
+        let what = format!("shortcode `{}`", tag.name);
+        let block = is_block(src, tag.start, tag.end);
+
+        if tag.closing {
+            let error = options.render_error(
+                line,
+                &what,
+                !block,
+                Box::from(format!("closing `{}` without an opening tag", tag.name)),
+            )?;
+            output.push_str(&single_line(&error));
+            pos = tag.end;
+            i += 1;
+            continue;
+        }
+
+        let closing = if tag.self_closing {
+            None
+        } else {
+            find_closing_tag(tags, i)
+        };
+        let (rendered, next) = match closing {
+            Some(j) => {
+                let body = expand_tags(
+                    src,
+                    &tags[i + 1..j],
+                    tag.end..tags[j].start,
+                    fences,
+                    options,
+                )?;
+                (render_shortcode(tag, Some(body), block, &options.images), j)
+            }
+            None => (render_shortcode(tag, None, block, &options.images), i),
+        };
+        let rendered = match rendered {
+            Ok(rendered) => rendered,
+            Err(e) => single_line(&options.render_error(line, &what, !block, e)?),
+        };
+        output.push_str(&rendered);
+        // an error stands in for the shortcode's body too, so make up for the body's lines
+        let lines = src[tag.start..tags[next].end].matches('\n').count();
+        let rendered_lines = rendered.matches('\n').count();
+        if rendered_lines < lines {
+            output.push_str(&"\n".repeat(lines - rendered_lines));
+        }
+        pos = tags[next].end;
+        i = next + 1;
+    }
+    push_source(&mut output, src, pos..range.end, fences);
+    Ok(output)
+}
+
+/// Expand `{{< name key="value" >}}` and `{{< name >}}body{{< /name >}}` shortcodes using the
+/// templates in `templates/shortcodes/`. Shortcodes in fenced code blocks are left alone, and
+/// `{{</* name */>}}` can be used to write out a shortcode without expanding it.
+pub fn expand_shortcodes(
+    src: &str,
+    options: &FormatOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let fences = fenced_ranges(src);
+    let tags: Vec<Tag> = SHORTCODE_REGEX
+        .captures_iter(src)
+        .filter_map(|caps| {
+            let m = caps.get(0).expect("match");
+            if fences.iter().any(|f| f.contains(&m.start())) {
+                return None;
+            }
+            Some(Tag {
+                start: m.start(),
+                end: m.end(),
+                name: caps.get(2).expect("shortcode name").as_str(),
+                args: caps.get(3).map(|a| a.as_str()).unwrap_or_default(),
+                closing: caps.get(1).is_some(),
+                self_closing: caps.get(4).is_some(),
+            })
+        })
+        .collect();
+    if tags.is_empty() && !ESCAPED_SHORTCODE_REGEX.is_match(src) {
+        return Ok(src.to_owned());
+    }
+
+    expand_tags(src, &tags, 0..src.len(), &fences, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_blank_lines_from_bodies() {
+        assert_eq!(split_blank_lines("\nbody\n"), (1, "body", 1));
+        assert_eq!(
+            split_blank_lines("  \n\n  indented\nmore\n\n\n"),
+            (2, "  indented\nmore", 3)
+        );
+        assert_eq!(split_blank_lines("inline"), (0, "inline", 0));
+        assert_eq!(split_blank_lines("\n \n"), (2, "", 0));
+        assert_eq!(split_blank_lines(""), (0, "", 0));
+    }
+
+    fn strict() -> FormatOptions {
+        FormatOptions {
+            strict: true,
+            ..FormatOptions::default()
+        }
+    }
+
+    #[test]
+    fn unknown_shortcodes_are_errors() {
+        let src = "before {{< nonexistent >}} after\n";
+        assert!(expand_shortcodes(src, &strict()).is_err());
+        let expanded = expand_shortcodes(src, &FormatOptions::default()).unwrap();
+        assert!(expanded.starts_with("before <span class=\"render-error\""));
+        assert!(expanded.ends_with(" after\n"));
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        assert!(expand_shortcodes(r#"{{< kbd key="unterminated >}}"#, &strict()).is_err());
+    }
+
+    #[test]
+    fn unmatched_closing_tags_are_errors() {
+        let src = "text\n\n{{< /kbd >}}\n";
+        assert!(expand_shortcodes(src, &strict()).is_err());
+        let expanded = expand_shortcodes(src, &FormatOptions::default()).unwrap();
+        assert!(expanded.contains("render-error"));
+        assert!(!expanded.contains("{{<"));
+    }
+
+    #[test]
+    fn block_bodies_stay_markdown_on_their_own_lines() {
+        let src = "{{< figure src=\"a.png\" >}}\nA *caption*\n{{< /figure >}}\n\nafter\n";
+        let expanded = expand_shortcodes(src, &strict()).unwrap();
+        assert!(expanded.starts_with("<!----><figure>"), "{}", expanded);
+        assert!(
+            expanded.contains("<figcaption>\nA *caption*\n<!----></figcaption>"),
+            "{}",
+            expanded
+        );
+        assert!(expanded.ends_with("</figure>\n\nafter\n"), "{}", expanded);
+    }
+
+    #[test]
+    fn keeps_the_number_of_lines() {
+        let src = "{{< kbd >}}\nCtrl\n\n{{< /kbd >}}\n{{< nonexistent >}}\nbody\n{{< /nonexistent >}}\nend\n";
+        let expanded = expand_shortcodes(src, &FormatOptions::default()).unwrap();
+        assert_eq!(
+            expanded.matches('\n').count(),
+            src.matches('\n').count(),
+            "{}",
+            expanded
+        );
+        assert!(expanded.ends_with("\nend\n"));
+    }
+
+    #[test]
+    fn escaped_shortcodes_are_written_out_except_in_code() {
+        let src = "{{</* kbd */>}}\n\n```\n{{</* kbd */>}}\n```\n";
+        assert_eq!(
+            expand_shortcodes(src, &strict()).unwrap(),
+            "{{< kbd >}}\n\n```\n{{</* kbd */>}}\n```\n"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use comrak::nodes::{Ast, AstNode, NodeValue};
+use comrak::{Arena, ComrakOptions};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// whether a footnote is a single paragraph, which can sit in a `<span>` inside the paragraph
+/// that refers to it, rather than block content (lists, code, several paragraphs) which can't
+fn is_inline_note<'a>(definition: &'a AstNode<'a>) -> bool {
+    let mut children = definition.children();
+    match (children.next(), children.next()) {
+        (Some(paragraph), None) => matches!(paragraph.data.borrow().value, NodeValue::Paragraph),
+        (None, _) => true,
+        _ => false,
+    }
+}
+
+/// render a footnote's contents, without the `<p>` around it if it is a single paragraph
+fn render_note<'a>(
+    definition: &'a AstNode<'a>,
+    options: &ComrakOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let inline = is_inline_note(definition);
+    let mut html: Vec<u8> = Vec::default();
+    for child in definition.children() {
+        if inline {
+            for inline in child.children() {
+                comrak::format_html(inline, options, &mut html)?;
+            }
+        } else {
+            comrak::format_html(child, options, &mut html)?;
+        }
+    }
+    Ok(String::from_utf8(html)?.trim().to_owned())
+}
+
+/// the block that a note with block content goes after: the paragraph, heading or table the
+/// reference is in, or failing that whatever at the top level of the document it is in
+fn enclosing_block<'a>(reference: &'a AstNode<'a>) -> Option<&'a AstNode<'a>> {
+    let mut top = None;
+    for ancestor in reference.ancestors().skip(1) {
+        match ancestor.data.borrow().value {
+            NodeValue::Paragraph | NodeValue::Heading(_) | NodeValue::Table(_) => {
+                return Some(ancestor)
+            }
+            NodeValue::Document => break,
+            _ => top = Some(ancestor),
+        }
+    }
+    top
+}
+
+/// Move the post's footnotes next to their references as Tufte style sidenotes, which sit in the
+/// margin on wide screens and are toggled open by their number on narrow ones. Footnotes whose
+/// names start with `margin` (i.e. `[^margin-aside]`) become unnumbered margin notes instead.
+/// Notes with block content can't go inside of a paragraph, so they go after the block their
+/// reference is in as an `<aside>`, leaving only their number at the reference.
+pub fn convert_footnotes_to_sidenotes<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    root: &'a AstNode<'a>,
+    options: &ComrakOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // comrak numbers the references in order, and appends the definitions in the same order
+    let definitions: Vec<(&'a AstNode<'a>, String)> = root
+        .children()
+        .filter_map(|node| match &node.data.borrow().value {
+            NodeValue::FootnoteDefinition(name) => {
+                Some((node, String::from_utf8_lossy(name).into_owned()))
+            }
+            _ => None,
+        })
+        .collect();
+    if definitions.is_empty() {
+        return Ok(());
+    }
+
+    let references: Vec<&'a AstNode<'a>> = root
+        .descendants()
+        .filter(|node| matches!(node.data.borrow().value, NodeValue::FootnoteReference(_)))
+        .collect();
+    // the number shown for each footnote, skipping margin notes
+    let mut numbers: BTreeMap<usize, usize> = BTreeMap::default();
+    // the block notes were last put after, and the last of the notes put after it
+    let mut last_block_note: Option<(&'a AstNode<'a>, &'a AstNode<'a>)> = None;
+    for reference in references {
+        let ix: usize = match &reference.data.borrow().value {
+            NodeValue::FootnoteReference(ix) => match String::from_utf8_lossy(ix).parse() {
+                Ok(ix) => ix,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        let (definition, name) = match ix.checked_sub(1).and_then(|i| definitions.get(i)) {
+            Some(d) => d,
+            None => continue,
+        };
+        let margin = name.starts_with("margin");
+        let inline = is_inline_note(definition);
+
+        let html = match numbers.get(&ix).copied() {
+            // later references to the same footnote toggle the note from the first reference
+            Some(0) => format!(
+                r#"<label for="sidenote-{}" class="margin-toggle">&#8853;</label>"#,
+                ix
+            ),
+            Some(number) => format!(
+                r#"<label for="sidenote-{}" class="margin-toggle sidenote-number">{}</label>"#,
+                ix, number
+            ),
+            None if margin && inline => {
+                numbers.insert(ix, 0);
+                format!(
+                    r#"<label for="sidenote-{ix}" class="margin-toggle">&#8853;</label><input type="checkbox" id="sidenote-{ix}" class="margin-toggle" /><span class="marginnote">{note}</span>"#,
+                    ix = ix,
+                    note = render_note(definition, options)?
+                )
+            }
+            None if inline => {
+                let number = numbers.values().filter(|n| **n > 0).count() + 1;
+                numbers.insert(ix, number);
+                format!(
+                    r#"<label for="sidenote-{ix}" class="margin-toggle sidenote-number">{number}</label><input type="checkbox" id="sidenote-{ix}" class="margin-toggle" /><span class="sidenote"><span class="sidenote-number">{number}</span> {note}</span>"#,
+                    ix = ix,
+                    number = number,
+                    note = render_note(definition, options)?
+                )
+            }
+            None => {
+                let note = render_note(definition, options)?;
+                let (label, note) = if margin {
+                    numbers.insert(ix, 0);
+                    (
+                        format!(
+                            r#"<label for="sidenote-{}" class="margin-toggle">&#8853;</label>"#,
+                            ix
+                        ),
+                        format!(r#"<aside class="marginnote">{}</aside>"#, note),
+                    )
+                } else {
+                    let number = numbers.values().filter(|n| **n > 0).count() + 1;
+                    numbers.insert(ix, number);
+                    // the number goes at the start of the note's first paragraph
+                    let number_html =
+                        format!(r#"<span class="sidenote-number">{}</span> "#, number);
+                    let note = match note.strip_prefix("<p>") {
+                        Some(rest) => format!("<p>{}{}", number_html, rest),
+                        None => format!("{}{}", number_html, note),
+                    };
+                    (
+                        format!(
+                            r#"<label for="sidenote-{}" class="margin-toggle sidenote-number">{}</label>"#,
+                            ix, number
+                        ),
+                        format!(r#"<aside class="sidenote">{}</aside>"#, note),
+                    )
+                };
+
+                let html = format!(
+                    r#"<input type="checkbox" id="sidenote-{}" class="margin-toggle" />{}"#,
+                    ix, note
+                );
+                let node = arena.alloc(comrak::arena_tree::Node::new(RefCell::new(Ast::new(
+                    NodeValue::HtmlInline(html.into_bytes()),
+                ))));
+                match enclosing_block(reference) {
+                    Some(block) => {
+                        match last_block_note {
+                            Some((last_block, last_note)) if std::ptr::eq(last_block, block) => {
+                                last_note.insert_after(node)
+                            }
+                            _ => block.insert_after(node),
+                        }
+                        last_block_note = Some((block, node));
+                    }
+                    None => root.append(node),
+                }
+                label
+            }
+        };
+        reference.data.borrow_mut().value = NodeValue::HtmlInline(html.into_bytes());
+    }
+
+    for (definition, _) in definitions {
+        definition.detach();
+    }
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use super::markdown::CodeBlock;
+use super::tool::{run_tool, TempDir};
+use crate::cache::{cached, hash_key};
+use crate::config::TestCommand;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static SNIPPET_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestResults {
+    pub passed: usize,
+    pub failures: Vec<String>,
+}
+
+fn test_command<'t>(
+    lang: &str,
+    tests: &'t BTreeMap<String, TestCommand>,
+) -> Result<&'t TestCommand, Box<dyn std::error::Error>> {
+    match tests.get(lang) {
+        Some(t) => Ok(t),
+        None => Err(Box::from(format!(
+            "don't know how to run `{}` snippets, add a `tests.{}` entry to config.yml",
+            lang, lang
+        ))),
+    }
+}
+
+/// compile and / or run a snippet with the configured toolchain, returning its stdout
+pub fn run_snippet(
+    src: &str,
+    lang: &str,
+    tests: &BTreeMap<String, TestCommand>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    use std::process::Command;
+
+    let test = test_command(lang, tests)?;
+
+    // snippets are run in parallel so give each one its own directory
+    let temp = TempDir::new(&format!(
+        "blogham-snippet-{}-{}",
+        hash_key(src),
+        SNIPPET_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ))?;
+    let dir = temp.path();
+    let file = dir.join(&test.file);
+    std::fs::write(&file, src)?;
+
+    let dir_arg = dir.to_string_lossy();
+    let file_arg = file.to_string_lossy();
+    let mut result: Result<String, Box<dyn std::error::Error>> = Ok(String::default());
+    for command in test.commands.iter() {
+        let args: Vec<String> = command
+            .iter()
+            .map(|arg| arg.replace("{dir}", &dir_arg).replace("{file}", &file_arg))
+            .collect();
+        result = match args.split_first() {
+            Some((program, args)) => {
+                run_tool(Command::new(program).args(args).current_dir(dir), "")
+            }
+            None => Err(Box::from(format!(
+                "empty test command for `{}` snippets",
+                lang
+            ))),
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+
+    result
+}
+
+/// the output of a snippet, for embedding into the page under the code
+pub fn snippet_output(
+    src: &str,
+    lang: &str,
+    tests: &BTreeMap<String, TestCommand>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let test = test_command(lang, tests)?;
+    let key = format!("{}\n{:?}\n{}", lang, test.commands, src);
+    cached("snippets", &key, || run_snippet(src, lang, tests))
+}
+
+/// run every code block marked `test`, collecting failures with where they came from
+pub fn test_code_blocks(
+    blocks: Vec<CodeBlock>,
+    source: &std::path::Path,
+    tests: &BTreeMap<String, TestCommand>,
+) -> TestResults {
+    use rayon::prelude::*;
+
+    let failures: Vec<Option<String>> = blocks
+        .par_iter()
+        .filter(|block| block.info.has("test"))
+        .map(
+            |block| match run_snippet(&block.source, &block.info.lang, tests) {
+                Ok(_) => None,
+                Err(e) => Some(format!("{}:{}: {}", source.display(), block.line, e)),
+            },
+        )
+        .collect();
+
+    TestResults {
+        passed: failures.iter().filter(|f| f.is_none()).count(),
+        failures: failures.into_iter().flatten().collect(),
+    }
+}
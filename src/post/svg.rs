@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+
+lazy_static::lazy_static! {
+    static ref COMMENT_REGEX: regex::Regex = regex::Regex::new(r#"(?s)<!--.*?-->"#).expect("valid regex");
+    /// `<metadata>`, and the elements and attributes that editors keep their own state in
+    static ref METADATA_REGEX: regex::Regex = regex::Regex::new(r#"(?s)<metadata\b.*?</metadata>|<metadata\b[^>]*/>|<sodipodi:namedview\b[^>]*/>|<sodipodi:namedview\b.*?</sodipodi:namedview>|<inkscape:[\w-]+\b[^>]*/>"#).expect("valid regex");
+    static ref EDITOR_ATTRIBUTE_REGEX: regex::Regex = regex::Regex::new(r#"\s(?:(?:inkscape|sodipodi):[\w-]+|xmlns:(?:inkscape|sodipodi|rdf|cc|dc))\s*=\s*(?:"[^"]*"|'[^']*')"#).expect("valid regex");
+    static ref GEOMETRY_ATTRIBUTE_REGEX: regex::Regex = regex::Regex::new(r#"\s(?:d|points|x|y|x1|y1|x2|y2|cx|cy|r|rx|ry|width|height)\s*=\s*(?:"[^"]*"|'[^']*')"#).expect("valid regex");
+    static ref NUMBER_REGEX: regex::Regex = regex::Regex::new(r#"-?\d*\.\d+(?:[eE][-+]?\d+)?"#).expect("valid regex");
+    static ref BETWEEN_TAGS_REGEX: regex::Regex = regex::Regex::new(r#">\s{2,}<"#).expect("valid regex");
+    static ref TAG_REGEX: regex::Regex = regex::Regex::new(r#"<(/?)([\w:-]+)([^>]*?)(/?)>"#).expect("valid regex");
+    static ref ID_REGEX: regex::Regex = regex::Regex::new(r#"(\sid\s*=\s*["'])([^"']*)(["'])"#).expect("valid regex");
+    /// `url(#id)`, `href="#id"` and `xlink:href="#id"`
+    static ref ID_REFERENCE_REGEX: regex::Regex = regex::Regex::new(r#"(url\(\s*["']?#|href\s*=\s*["']#)([^"')\s]+)"#).expect("valid regex");
+    static ref ARIA_REFERENCE_REGEX: regex::Regex = regex::Regex::new(r#"(\saria-(?:labelledby|describedby)\s*=\s*["'])([^"']*)(["'])"#).expect("valid regex");
+    static ref STYLE_REGEX: regex::Regex = regex::Regex::new(r#"(?s)(<style\b[^>]*>)(.*?)(</style>)"#).expect("valid regex");
+    static ref CSS_ID_REGEX: regex::Regex = regex::Regex::new(r#"#([\w-]+)"#).expect("valid regex");
+}
+
+/// drop everything preceding the `<svg>` element (xml declaration, doctype, generator comments)
+/// so the svg can be inlined into the page
+pub fn strip_prolog(svg: &str) -> Result<String, Box<dyn std::error::Error>> {
+    match svg.find("<svg") {
+        Some(start) => Ok(svg[start..].to_owned()),
+        None => Err(Box::from("expected an svg but didn't find one")),
+    }
+}
+
+/// round a number to 3 decimal places, dropping any trailing zeros
+fn shorten_number(number: &str) -> String {
+    let value: f64 = match number.parse() {
+        Ok(value) => value,
+        Err(_) => return number.to_owned(),
+    };
+    let shortened = format!("{:.3}", value);
+    let shortened = shortened.trim_end_matches('0').trim_end_matches('.');
+    match shortened {
+        "-0" | "" => "0".to_owned(),
+        shortened => shortened.to_owned(),
+    }
+}
+
+/// unwrap `<g>`s without any attributes, which do nothing, and drop groups with nothing in them.
+/// Groups with an id are always kept, as something may refer to them.
+fn collapse_groups(svg: &str) -> String {
+    let mut output = String::with_capacity(svg.len());
+    // for each open group: where it starts in the output, where its contents start and whether
+    // it has to be kept
+    let mut groups: Vec<(usize, usize, bool)> = Vec::default();
+    let mut last = 0;
+    for caps in TAG_REGEX.captures_iter(svg) {
+        let tag = caps.get(0).expect("whole match");
+        if &caps[2] != "g" {
+            continue;
+        }
+        output.push_str(&svg[last..tag.start()]);
+        last = tag.end();
+
+        let closing = !caps[1].is_empty();
+        let self_closing = !caps[4].is_empty();
+        if closing {
+            match groups.pop() {
+                Some((start, contents, false)) if output.len() == contents => {
+                    output.truncate(start)
+                }
+                // the group's opening tag was dropped, so this one is too
+                Some((start, contents, _)) if start == contents => {}
+                _ => output.push_str(tag.as_str()),
+            }
+        } else if !self_closing {
+            let start = output.len();
+            if !caps[3].trim().is_empty() {
+                output.push_str(tag.as_str());
+            }
+            groups.push((start, output.len(), ID_REGEX.is_match(tag.as_str())));
+        }
+    }
+    output.push_str(&svg[last..]);
+    output
+}
+
+/// Shrink an svg: drop comments and editor metadata, unwrap or drop groups that do nothing, round
+/// the numbers in its shapes and paths and squash the indentation between its tags. Ids are left
+/// alone, so that anything linking into the svg (i.e. `icons.svg#note`) still works.
+pub fn optimise(svg: &str) -> String {
+    let svg = COMMENT_REGEX.replace_all(svg, "");
+    let svg = METADATA_REGEX.replace_all(&svg, "");
+    let svg = EDITOR_ATTRIBUTE_REGEX.replace_all(&svg, "");
+    // transforms and the view box scale everything inside of them, so they keep their precision
+    let svg = GEOMETRY_ATTRIBUTE_REGEX.replace_all(&svg, |caps: &regex::Captures| {
+        NUMBER_REGEX
+            .replace_all(&caps[0], |number: &regex::Captures| {
+                shorten_number(&number[0])
+            })
+            .into_owned()
+    });
+    let svg = collapse_groups(&svg);
+    // runs of whitespace only ever render as a single space, unless told to be kept as they are,
+    // which also takes care of the indentation left by everything dropped above
+    if svg.contains("xml:space") {
+        svg
+    } else {
+        BETWEEN_TAGS_REGEX.replace_all(&svg, "> <").into_owned()
+    }
+}
+
+/// Prefix every id in an svg, along with everything in it that refers to them (`url(#id)`,
+/// `href="#id"`, aria attributes and the svg's own stylesheets)
+fn prefix_ids(svg: &str, prefix: &str) -> String {
+    let ids: HashSet<String> = ID_REGEX
+        .captures_iter(svg)
+        .map(|caps| caps[2].to_owned())
+        .collect();
+    if ids.is_empty() {
+        return svg.to_owned();
+    }
+    let prefixed = |id: &str| {
+        if ids.contains(id) {
+            format!("{}{}", prefix, id)
+        } else {
+            id.to_owned()
+        }
+    };
+
+    let svg = ID_REGEX.replace_all(svg, |caps: &regex::Captures| {
+        format!("{}{}{}", &caps[1], prefixed(&caps[2]), &caps[3])
+    });
+    let svg = ID_REFERENCE_REGEX.replace_all(&svg, |caps: &regex::Captures| {
+        format!("{}{}", &caps[1], prefixed(&caps[2]))
+    });
+    let svg = ARIA_REFERENCE_REGEX.replace_all(&svg, |caps: &regex::Captures| {
+        let references: Vec<String> = caps[2].split_whitespace().map(|id| prefixed(id)).collect();
+        format!("{}{}{}", &caps[1], references.join(" "), &caps[3])
+    });
+    let svg = STYLE_REGEX.replace_all(&svg, |caps: &regex::Captures| {
+        let css = CSS_ID_REGEX.replace_all(&caps[2], |id: &regex::Captures| {
+            format!("#{}", prefixed(&id[1]))
+        });
+        format!("{}{}{}", &caps[1], css, &caps[3])
+    });
+    svg.into_owned()
+}
+
+/// the length of the svg element at the start of `html`, including any svgs nested in it
+fn svg_len(html: &str) -> usize {
+    let mut depth = 0;
+    let mut i = 0;
+    while let Some(next) = html[i..].find('<') {
+        i += next;
+        if html[i..].starts_with("<svg") {
+            depth += 1;
+        } else if html[i..].starts_with("</svg>") {
+            depth -= 1;
+            if depth == 0 {
+                return i + "</svg>".len();
+            }
+        }
+        i += 1;
+    }
+    html.len()
+}
+
+/// Give the ids in each svg inlined into a page their own prefix (`svg1-`, `svg2-` and so on),
+/// as diagramming tools all number their ids the same way and ids that collide between diagrams
+/// make them render each other's markers, clip paths and glyphs
+pub fn prefix_inline_svg_ids(html: &str) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+    let mut count = 0;
+    while let Some(start) = rest.find("<svg") {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let len = svg_len(rest);
+        count += 1;
+        output.push_str(&prefix_ids(&rest[..len], &format!("svg{}-", count)));
+        rest = &rest[len..];
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortens_numbers() {
+        assert_eq!(shorten_number("1.23456"), "1.235");
+        assert_eq!(shorten_number("1.50000"), "1.5");
+        assert_eq!(shorten_number("2.0"), "2");
+        assert_eq!(shorten_number(".5"), "0.5");
+        assert_eq!(shorten_number("-0.0001"), "0");
+        assert_eq!(shorten_number("1.5e-7"), "0");
+    }
+
+    #[test]
+    fn collapses_groups_that_do_nothing() {
+        assert_eq!(
+            collapse_groups(r#"<svg><g><g fill="red"><path d="M0 0"/></g></g></svg>"#),
+            r#"<svg><g fill="red"><path d="M0 0"/></g></svg>"#
+        );
+        assert_eq!(
+            collapse_groups(r#"<svg><g class="empty"><g></g></g><g/></svg>"#),
+            "<svg></svg>"
+        );
+    }
+
+    #[test]
+    fn keeps_groups_with_ids() {
+        let svg = r#"<svg><g id="layer1"></g><g><g id='icon'><g></g></g></g></svg>"#;
+        assert_eq!(
+            collapse_groups(svg),
+            r#"<svg><g id="layer1"></g><g id='icon'></g></svg>"#
+        );
+    }
+
+    #[test]
+    fn optimises_svgs() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape" viewBox="0 0 10.000 10.000">
+  <!-- made with an editor -->
+  <metadata><rdf:RDF/></metadata>
+  <sodipodi:namedview inkscape:zoom="1.5"/>
+  <g>
+    <path inkscape:label="line" d="M 0.123456,1.000000 L 9.99999,2.5" fill="#a0b0c0"/>
+  </g>
+  <text>1.23456 stays</text></svg>"##;
+        assert_eq!(
+            optimise(svg),
+            r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10.000 10.000"> <path d="M 0.123,1 L 10,2.5" fill="#a0b0c0"/> <text>1.23456 stays</text></svg>"##
+        );
+    }
+
+    #[test]
+    fn keeps_transforms_as_they_are() {
+        let svg = r#"<svg viewBox="0 0 0.5 0.5"><g transform="matrix(0.0004 0 0 -0.0004 0.1234 0.5)"><path d="M 1.23456,0"/></g></svg>"#;
+        assert_eq!(
+            optimise(svg),
+            r#"<svg viewBox="0 0 0.5 0.5"><g transform="matrix(0.0004 0 0 -0.0004 0.1234 0.5)"><path d="M 1.235,0"/></g></svg>"#
+        );
+    }
+
+    #[test]
+    fn keeps_whitespace_when_asked_to() {
+        let svg = "<svg xml:space=\"preserve\"><text>a</text>\n  <text>b</text></svg>";
+        assert_eq!(optimise(svg), svg);
+    }
+
+    #[test]
+    fn prefixes_colliding_ids_in_each_inline_svg() {
+        let diagram = r##"<svg><defs><marker id="arrow"/><clipPath id="clip"/></defs><style>#arrow { fill: red; }</style><path marker-end="url(#arrow)" clip-path="url('#clip')"/><use xlink:href="#arrow" aria-labelledby="arrow title"/><a href="#elsewhere"/></svg>"##;
+        let html = format!("<p>one</p>{}<p>two</p>{}", diagram, diagram);
+        let prefixed = prefix_inline_svg_ids(&html);
+        for prefix in ["svg1-", "svg2-"].iter() {
+            let expected = format!(
+                r##"<svg><defs><marker id="{p}arrow"/><clipPath id="{p}clip"/></defs><style>#{p}arrow {{ fill: red; }}</style><path marker-end="url(#{p}arrow)" clip-path="url('#{p}clip')"/><use xlink:href="#{p}arrow" aria-labelledby="{p}arrow title"/><a href="#elsewhere"/></svg>"##,
+                p = prefix
+            );
+            assert!(prefixed.contains(&expected), "{}", prefixed);
+        }
+        assert!(prefixed.starts_with("<p>one</p><svg>"));
+    }
+
+    #[test]
+    fn nested_svgs_share_their_outer_svgs_prefix() {
+        let html = r#"<svg id="outer"><svg id="inner"></svg></svg><svg id="outer"></svg>"#;
+        assert_eq!(
+            prefix_inline_svg_ids(html),
+            r#"<svg id="svg1-outer"><svg id="svg1-inner"></svg></svg><svg id="svg2-outer"></svg>"#
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use super::codeinfo::CodeInfo;
+use super::svg::strip_prolog;
+use super::tool::{run_tool, TempDir, ToolError};
+use crate::cache::{cached, hash_key};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static RENDER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// wrap a tikz snippet in a standalone document, the snippet can either be a full
+/// `tikzpicture` environment or just its contents
+fn create_document(src: &str, info: &CodeInfo) -> String {
+    let mut document = String::from("\\documentclass[tikz,border=2pt]{standalone}\n");
+    if let Some(libraries) = info.get("libraries") {
+        document.push_str(&format!("\\usetikzlibrary{{{}}}\n", libraries));
+    }
+    if let Some(preamble) = info.get("preamble") {
+        document.push_str(preamble);
+        document.push('\n');
+    }
+    document.push_str("\\begin{document}\n");
+    if src.contains("\\begin{tikzpicture}") {
+        document.push_str(src);
+    } else {
+        document.push_str("\\begin{tikzpicture}\n");
+        document.push_str(src);
+        document.push_str("\\end{tikzpicture}\n");
+    }
+    document.push_str("\\end{document}\n");
+    document
+}
+
+fn render_tikz(document: &str) -> Result<String, Box<dyn std::error::Error>> {
+    use std::process::Command;
+
+    // posts render in parallel and may share a picture, so give each render its own directory
+    let temp = TempDir::new(&format!(
+        "blogham-tikz-{}-{}-{}",
+        hash_key(document),
+        std::process::id(),
+        RENDER_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ))?;
+    let dir = temp.path();
+    std::fs::write(dir.join("figure.tex"), document)?;
+
+    let result = run_tool(
+        Command::new("pdflatex")
+            .current_dir(dir)
+            .arg("-interaction=nonstopmode")
+            .arg("-halt-on-error")
+            .arg("figure.tex"),
+        "",
+    )
+    .map_err(|e| -> Box<dyn std::error::Error> {
+        // latex reports its errors in the log rather than on stderr
+        let log = std::fs::read_to_string(dir.join("figure.log")).unwrap_or_default();
+        let errors: Vec<&str> = log
+            .lines()
+            .skip_while(|line| !line.starts_with('!'))
+            .take(8)
+            .collect();
+        match e.downcast::<ToolError>() {
+            Ok(mut e) => {
+                e.stderr = errors.join("\n");
+                e as Box<dyn std::error::Error>
+            }
+            Err(e) => e,
+        }
+    })
+    .and_then(|_| {
+        run_tool(
+            Command::new("dvisvgm")
+                .current_dir(dir)
+                .arg("--pdf")
+                .arg("--no-fonts")
+                .arg("--stdout")
+                .arg("figure.pdf"),
+            "",
+        )
+    });
+    strip_prolog(&result?)
+}
+
+pub fn create_tikz_svg(src: &str, info: &CodeInfo) -> Result<String, Box<dyn std::error::Error>> {
+    let document = create_document(src, info);
+    cached("tikz", &document, || render_tikz(&document))
+}
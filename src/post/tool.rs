@@ -0,0 +1,89 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// An external tool (katex, plantuml, pygmentize, ...) that couldn't render its input
+#[derive(Debug)]
+pub struct ToolError {
+    pub tool: String,
+    pub code: Option<i32>,
+    pub stderr: String,
+}
+
+impl std::fmt::Display for ToolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{} failed with exit code {}", self.tool, code)?,
+            None => write!(f, "{} failed", self.tool)?,
+        }
+        if !self.stderr.trim().is_empty() {
+            write!(f, ":\n{}", self.stderr.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ToolError {}
+
+/// A scratch directory under the system's temp directory for a tool to work in, removed along
+/// with everything in it when dropped. Failing to clean up is only logged, so that it never
+/// hides what the tool itself did.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> std::io::Result<TempDir> {
+        let path = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&path)?;
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            eprintln!("failed to remove `{}`: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// run `command`, feeding it `input` over stdin and collecting stdout
+pub fn run_tool(command: &mut Command, input: &str) -> Result<String, Box<dyn std::error::Error>> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let tool = command.get_program().to_string_lossy().into_owned();
+    let mut child = match command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(Box::new(ToolError {
+                tool,
+                code: None,
+                stderr: format!("failed to launch: {}", e),
+            }));
+        }
+    };
+
+    let stdin = child.stdin.as_mut().expect("valid tool stdin");
+    stdin.write_all(input.as_ref())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Box::new(ToolError {
+            tool,
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(output.stderr.as_ref()).into_owned(),
+        }));
+    }
+    let rendered: String = String::from_utf8(output.stdout)?;
+    Ok(rendered)
+}